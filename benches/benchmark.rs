@@ -2,43 +2,18 @@ use criterion::{criterion_group, criterion_main, Criterion};
 use domain_matcher::ac_automaton::{ACAutomaton, HybridMatcher};
 use domain_matcher::geosite::SiteGroupList;
 use domain_matcher::mph::MphMatcher;
-use domain_matcher::{geosite, DomainMatcher, MatchType};
-use std::fs::File;
+use domain_matcher::{geosite, DomainMatcher};
 
 pub fn read_file(matcher: &mut impl DomainMatcher) -> SiteGroupList {
     let file = "data/geosite.dat";
-    let mut f = match File::open(&file) {
-        Ok(f) => f,
-        Err(e) => {
-            panic!("open dat file {} failed: {}", file, e);
-        }
-    };
-    let geosite_list: geosite::SiteGroupList = match protobuf::Message::parse_from_reader(&mut f) {
+    let geosite_list = match geosite::load_site_group_list(file) {
         Ok(v) => v,
         Err(e) => {
-            panic!("dat file {} has invalid format: {}", file, e);
+            panic!("load dat file {} failed: {}", file, e);
         }
     };
-    for i in geosite_list.site_group.iter() {
-        if i.tag.to_uppercase() == "CN" {
-            for domain in i.domain.iter() {
-                match domain.field_type {
-                    geosite::Domain_Type::Plain => {
-                        matcher.reverse_insert(domain.get_value(), MatchType::SubStr(true))
-                    }
-                    geosite::Domain_Type::Domain => {
-                        matcher.reverse_insert(domain.get_value(), MatchType::Domain(true))
-                    }
-                    geosite::Domain_Type::Full => {
-                        matcher.reverse_insert(domain.get_value(), MatchType::Full(true))
-                    }
-                    _ => {}
-                }
-            }
-            break;
-        }
-    }
-    matcher.build();
+    geosite::insert_group(matcher, geosite::find_group(&geosite_list, "cn").unwrap());
+    matcher.build().unwrap();
     geosite_list
 }
 
@@ -98,9 +73,26 @@ fn benchmark_mph_matcher(c: &mut Criterion) {
         })
     });
 }
+
+fn benchmark_mph_matcher_batch(c: &mut Criterion) {
+    let mut mph_matcher = MphMatcher::new(1);
+    let geosite_list = read_file(&mut mph_matcher);
+    let queries: Vec<&str> = geosite_list
+        .site_group
+        .iter()
+        .flat_map(|i| i.domain.iter())
+        .filter(|domain| domain.field_type != geosite::Domain_Type::Regex)
+        .map(|domain| domain.get_value())
+        .collect();
+    c.bench_function("benchmark_mph_matcher_batch", |b| {
+        b.iter(|| mph_matcher.reverse_query_batch(&queries))
+    });
+}
+
 criterion_group!(
     benches,
     benchmark_mph_matcher,
+    benchmark_mph_matcher_batch,
     benchmark_hybrid_matcher,
     benchmark_ac_automaton
 );
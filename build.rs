@@ -1,8 +1,25 @@
+use std::env;
+use std::fs;
+use std::path::Path;
+
 fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/geosite.proto");
+    let out_dir = env::var("OUT_DIR").unwrap();
     protobuf_codegen_pure::Codegen::new()
-        .out_dir("src/")
-        .inputs(&["src/geosite.proto"])
+        .out_dir(&out_dir)
+        .inputs(["src/geosite.proto"])
         .include(".")
         .run()
         .expect("protoc");
+    // `include!` takes no inner attributes or docs, so they are dropped and
+    // `src/geosite/mod.rs` sets the lints of the module instead.
+    let path = Path::new(&out_dir).join("geosite.rs");
+    let code = fs::read_to_string(&path).expect("geosite.rs");
+    let code: String = code
+        .lines()
+        .filter(|line| !line.starts_with("#!") && !line.starts_with("//!"))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    fs::write(&path, code).expect("geosite.rs");
 }
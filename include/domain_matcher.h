@@ -0,0 +1,143 @@
+/* Link against the library built with `cargo rustc --release --lib --features ffi --crate-type cdylib` (or `staticlib`). */
+
+#ifndef DOMAIN_MATCHER_H
+#define DOMAIN_MATCHER_H
+
+/* Generated by cbindgen from src/ffi.rs with `cbindgen --config cbindgen.toml --output include/domain_matcher.h`. Do not edit. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+typedef enum DmMatchType {
+  DM_MATCH_TYPE_DOMAIN = 0,
+  DM_MATCH_TYPE_SUB_STR,
+  DM_MATCH_TYPE_FULL,
+  DM_MATCH_TYPE_REGEX,
+} DmMatchType;
+
+typedef enum DmStatus {
+  DM_STATUS_OK = 0,
+  DM_STATUS_NULL_POINTER,
+  DM_STATUS_INVALID_UTF8,
+  /**
+   * `build()` failed, see `BuildError`.
+   */
+  DM_STATUS_BUILD,
+  DM_STATUS_IO,
+  /**
+   * The geosite.dat file is not valid protobuf.
+   */
+  DM_STATUS_INVALID_DATA,
+  DM_STATUS_UNKNOWN_TAG,
+  DM_STATUS_INVALID_SELECTOR,
+  DM_STATUS_PANIC,
+  /**
+   * A match type outside `DmMatchType`.
+   */
+  DM_STATUS_INVALID_MATCH_TYPE,
+} DmStatus;
+
+/**
+ * An `MphMatcher` owned by C code.
+ */
+typedef struct DmMatcher DmMatcher;
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+/**
+ * Returns a static description of the `DmStatus` value `status`.
+ */
+const char *dm_status_str(uint32_t status);
+
+/**
+ * Creates an empty matcher that ignores ASCII case. Free it with
+ * `dm_matcher_free`.
+ */
+struct DmMatcher *dm_matcher_new(void);
+
+/**
+ * Creates an empty matcher that tells ASCII upper and lower case apart.
+ */
+struct DmMatcher *dm_matcher_new_case_sensitive(void);
+
+/**
+ * Frees a matcher. Null is ignored.
+ *
+ * # Safety
+ *
+ * `matcher` must be null or come from this API and not be freed yet.
+ */
+void dm_matcher_free(struct DmMatcher *matcher);
+
+/**
+ * Inserts a rule of the `DmMatchType` value `match_type`. With `exception`
+ * set, the rule carves matching domains out of the others.
+ *
+ * # Safety
+ *
+ * `matcher` must be a live matcher and `pattern` a NUL terminated string.
+ */
+enum DmStatus dm_matcher_insert(struct DmMatcher *matcher,
+                                const char *pattern,
+                                uint32_t match_type,
+                                bool exception);
+
+/**
+ * Removes the rules inserted as `pattern` with `match_type` and `exception`,
+ * and sets `*removed` to whether there were any.
+ *
+ * # Safety
+ *
+ * `matcher` must be a live matcher, `pattern` a NUL terminated string and
+ * `removed` null or writable.
+ */
+enum DmStatus dm_matcher_remove(struct DmMatcher *matcher,
+                                const char *pattern,
+                                uint32_t match_type,
+                                bool exception,
+                                bool *removed);
+
+/**
+ * Prepares the inserted rules for queries.
+ *
+ * # Safety
+ *
+ * `matcher` must be a live matcher.
+ */
+enum DmStatus dm_matcher_build(struct DmMatcher *matcher);
+
+/**
+ * Sets `*matched` to whether `domain` matches a rule of a built matcher.
+ *
+ * # Safety
+ *
+ * `matcher` must be a live matcher, `domain` a NUL terminated string and
+ * `matched` writable. Queries may run on many threads at once, but not
+ * together with a call that changes the matcher.
+ */
+enum DmStatus dm_matcher_query(const struct DmMatcher *matcher, const char *domain, bool *matched);
+
+/**
+ * Loads the group selected by `selector`, e.g. `google@cn`, from the
+ * geosite.dat file at `path` into a new built matcher stored in `*matcher`.
+ * On error `*matcher` is set to null.
+ *
+ * # Safety
+ *
+ * `path` and `selector` must be NUL terminated strings and `matcher`
+ * writable.
+ */
+enum DmStatus dm_matcher_load_geosite(const char *path,
+                                      const char *selector,
+                                      struct DmMatcher **matcher);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* DOMAIN_MATCHER_H */
@@ -1,8 +1,14 @@
-use crate::{DomainMatcher, MatchType};
+use crate::index::{decode_match_type, encode_match_type, FormatError, Reader, U32Array, Writer};
+use crate::regex_matcher::RegexMatcher;
+use crate::{
+    key_owner, live_rules, normalize_query, remove_rules, takes_key, BuildError, DomainMatcher,
+    MatchType, MatchedRule, Matches, Rule, NO_RULE,
+};
 use deepsize::Context;
 use deepsize::DeepSizeOf;
 use std::collections::HashMap;
 use std::collections::VecDeque;
+use std::mem::discriminant;
 use std::num::Wrapping;
 
 const fn count_host_valid_character() -> usize {
@@ -16,86 +22,252 @@ const PRIMEFK: Wrapping<RollingHashType> = Wrapping(16777619);
 const OFFSETS: Wrapping<RollingHashType> = Wrapping(0);
 
 #[derive(DeepSizeOf)]
-pub struct HybridMatcher {
+pub struct HybridMatcher<V = ()> {
     ac: ACAutomaton,
-    map: HashMap<RollingHashType, Vec<String>>,
+    regex: RegexMatcher,
+    patterns: Vec<Rule<V>>,
+    map: HashMap<RollingHashType, Vec<(String, u32)>>,
+    case_sensitive: bool,
 }
 
-impl DomainMatcher for HybridMatcher {
-    fn reverse_insert(&mut self, input_string: &str, match_type: MatchType) {
+impl<V> DomainMatcher<V> for HybridMatcher<V> {
+    fn reverse_insert(&mut self, input_string: &str, match_type: MatchType, value: V) {
+        let rule_id = self.patterns.len() as u32;
+        self.patterns
+            .push(Rule::new(input_string, match_type, value));
+        let key = self.key(input_string);
         let mut h = OFFSETS;
-        for c in input_string.bytes().rev() {
+        for c in key.bytes().rev() {
             h = h * PRIMEFK + Wrapping(c as RollingHashType);
         }
         match match_type {
-            MatchType::SubStr(_) => self.ac.reverse_insert(input_string, match_type),
+            MatchType::SubStr(_) => self.ac.insert_with_id(input_string, match_type, rule_id),
             MatchType::Domain(_) => {
-                self.insert(h.0, input_string.to_string());
                 self.insert(
                     (h * PRIMEFK + Wrapping(b'.' as RollingHashType)).0,
-                    format!(".{}", input_string),
+                    format!(".{}", key),
+                    rule_id,
                 );
+                self.insert(h.0, key, rule_id);
             }
             MatchType::Full(_) => {
-                self.insert(h.0, input_string.to_string());
+                self.insert(h.0, key, rule_id);
             }
+            MatchType::Regex(_) => self.regex.insert(input_string, rule_id),
         }
     }
+    fn remove(&mut self, pattern: &str, match_type: MatchType) -> bool {
+        let ids = remove_rules(&mut self.patterns, pattern, match_type, self.case_sensitive);
+        for id in ids.iter() {
+            match match_type {
+                MatchType::SubStr(_) => self.ac.remove_with_id(
+                    pattern,
+                    match_type,
+                    *id,
+                    live_rules(&self.patterns)
+                        .filter(|(_, rule)| matches!(rule.match_type, MatchType::SubStr(_))),
+                ),
+                MatchType::Domain(_) => {
+                    let key = self.key(pattern);
+                    self.unlink(format!(".{}", key), *id);
+                    self.unlink(key, *id);
+                }
+                MatchType::Full(_) => self.unlink(self.key(pattern), *id),
+                MatchType::Regex(_) => self.regex.remove(*id),
+            }
+        }
+        !ids.is_empty()
+    }
     fn reverse_query(&self, query_string: &str) -> bool {
+        self.matched_rule_id(query_string).is_some()
+    }
+    fn reverse_query_rule(&self, query_string: &str) -> Option<MatchedRule<'_, V>> {
+        self.matched_rule_id(query_string)
+            .map(|id| self.patterns[id as usize].matched(id as usize))
+    }
+    fn reverse_query_all(&self, query_string: &str) -> Matches<'_, V> {
+        let query_string = match normalize_query(query_string) {
+            Some(query_string) => query_string,
+            None => return Matches::new(&self.patterns, Vec::new()),
+        };
+        let mut rule_ids = Vec::new();
         let mut h = OFFSETS;
         let mut idx = Wrapping(query_string.len() - 1);
         for c in query_string.bytes().rev() {
-            h = h * PRIMEFK + Wrapping(c as RollingHashType);
+            h = h * PRIMEFK + Wrapping(self.fold(c) as RollingHashType);
             if c == b'.' {
-                match self.map.get(&h.0) {
-                    Some(v) if v.iter().any(|x| x == &query_string[idx.0..]) => {
-                        return true;
-                    }
-                    _ => {}
+                if let Some(id) = self.lookup(h.0, &query_string[idx.0..]) {
+                    rule_ids.push(id);
                 }
             }
             idx -= Wrapping(1);
         }
-        return match self.map.get(&h.0) {
-            Some(v) if v.iter().any(|x| x == query_string) => true,
-            _ => {
-                if !self.ac.empty() {
-                    self.ac.reverse_query(query_string)
-                } else {
-                    false
-                }
-            }
-        };
-    }
-    fn build(&mut self) {
+        if let Some(id) = self.lookup(h.0, query_string) {
+            rule_ids.push(id);
+        }
+        rule_ids.reverse();
         if !self.ac.empty() {
-            self.ac.build()
+            self.ac.collect_rule_ids(query_string, &mut rule_ids);
         }
+        if !self.regex.is_empty() {
+            self.regex.collect_rule_ids(
+                query_string,
+                |id| self.patterns[id as usize].match_type.is_exception(),
+                &mut rule_ids,
+            );
+        }
+        Matches::new(&self.patterns, rule_ids)
+    }
+    fn build(&mut self) -> Result<(), BuildError> {
+        self.ac.build()?;
+        self.regex.build()
     }
 
     fn clear(&mut self) {
         self.ac.clear();
+        self.regex.clear();
+        self.patterns.clear();
         self.map.clear();
     }
 }
 
-impl HybridMatcher {
-    pub fn new(size: usize) -> HybridMatcher {
+impl<V> HybridMatcher<V> {
+    /// Creates a matcher that ignores ASCII case.
+    pub fn new(size: usize) -> HybridMatcher<V> {
+        HybridMatcher::with_case(size, false)
+    }
+
+    /// Creates a matcher that tells ASCII upper and lower case apart.
+    pub fn new_case_sensitive(size: usize) -> HybridMatcher<V> {
+        HybridMatcher::with_case(size, true)
+    }
+
+    fn with_case(size: usize, case_sensitive: bool) -> HybridMatcher<V> {
         HybridMatcher {
-            ac: ACAutomaton::new(size),
+            ac: ACAutomaton::with_case(size, case_sensitive),
+            regex: RegexMatcher::new(case_sensitive),
+            patterns: Vec::new(),
             map: HashMap::new(),
+            case_sensitive,
         }
     }
 
-    fn insert(&mut self, h: RollingHashType, s: String) {
+    pub fn is_case_sensitive(&self) -> bool {
+        self.case_sensitive
+    }
+
+    #[inline(always)]
+    fn fold(&self, c: u8) -> u8 {
+        if self.case_sensitive {
+            c
+        } else {
+            c.to_ascii_lowercase()
+        }
+    }
+
+    // Hash keys are stored lowercase unless the matcher is case sensitive.
+    fn key(&self, pattern: &str) -> String {
+        if self.case_sensitive {
+            pattern.to_string()
+        } else {
+            pattern.to_ascii_lowercase()
+        }
+    }
+
+    // Hands `key` from the removed rule `id` to the first live rule owning
+    // it, or drops it if there is none.
+    fn unlink(&mut self, key: String, id: u32) {
+        let mut h = OFFSETS;
+        for c in key.bytes().rev() {
+            h = h * PRIMEFK + Wrapping(c as RollingHashType);
+        }
+        let next = key_owner(&self.patterns, &key, self.case_sensitive);
+        if let Some(v) = self.map.get_mut(&h.0) {
+            if let Some(pos) = v
+                .iter()
+                .position(|(x, rule_id)| *x == key && *rule_id == id)
+            {
+                match next {
+                    Some(next) => v[pos].1 = next,
+                    None => {
+                        v.remove(pos);
+                    }
+                }
+            }
+            if v.is_empty() {
+                self.map.remove(&h.0);
+            }
+        }
+    }
+
+    fn insert(&mut self, h: RollingHashType, s: String, rule_id: u32) {
         if let Some(v) = self.map.get_mut(&h) {
-            if !v.contains(&s) {
-                v.push(s);
+            match v.iter_mut().find(|(x, _)| *x == s) {
+                Some(old) if takes_key(&self.patterns, old.1, rule_id) => old.1 = rule_id,
+                Some(_) => {}
+                None => v.push((s, rule_id)),
+            }
+        } else {
+            self.map.insert(h, vec![(s, rule_id)]);
+        }
+    }
+
+    #[inline(always)]
+    fn lookup(&self, h: RollingHashType, query_string: &str) -> Option<u32> {
+        match self.map.get(&h) {
+            Some(v) => v
+                .iter()
+                .find(|(x, _)| {
+                    if self.case_sensitive {
+                        x == query_string
+                    } else {
+                        x.eq_ignore_ascii_case(query_string)
+                    }
+                })
+                .map(|(_, rule_id)| *rule_id),
+            None => None,
+        }
+    }
+
+    /// Returns the rule deciding `query_string`, which may be an exception.
+    fn query_rule_id(&self, query_string: &str) -> Option<u32> {
+        // the longest matching suffix is the most specific rule
+        let mut found = None;
+        let mut h = OFFSETS;
+        let mut idx = Wrapping(query_string.len() - 1);
+        for c in query_string.bytes().rev() {
+            h = h * PRIMEFK + Wrapping(self.fold(c) as RollingHashType);
+            if c == b'.' {
+                if let Some(id) = self.lookup(h.0, &query_string[idx.0..]) {
+                    found = Some(id);
+                }
             }
+            idx -= Wrapping(1);
+        }
+        if let Some(id) = self.lookup(h.0, query_string) {
+            return Some(id);
+        }
+        if found.is_some() {
+            return found;
+        }
+        if !self.ac.empty() {
+            if let Some(id) = self.ac.query_rule_id(query_string) {
+                return Some(id);
+            }
+        }
+        if !self.regex.is_empty() {
+            self.regex.query_rule_id(query_string, |id| {
+                self.patterns[id as usize].match_type.is_exception()
+            })
         } else {
-            self.map.insert(h, vec![s]);
+            None
         }
     }
+
+    fn matched_rule_id(&self, query_string: &str) -> Option<u32> {
+        self.query_rule_id(normalize_query(query_string)?)
+            .filter(|id| !self.patterns[*id as usize].match_type.is_exception())
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -113,133 +285,481 @@ impl EdgeType {
     }
 }
 
-pub struct ACAutomaton {
-    trie: Vec<[EdgeType; count_host_valid_character()]>,
+pub struct ACAutomaton<V = ()> {
+    // `alphabet` edges per node
+    trie: Vec<EdgeType>,
     fail: Vec<usize>,
-    exists: Vec<MatchType>,
+    // match type and rule id of the Full or Domain pattern ending at each
+    // node, and of the SubStr pattern, which a node holds besides it
+    exists: Vec<Option<(MatchType, u32)>>,
+    substr: Vec<Option<(MatchType, u32)>>,
+    regex: RegexMatcher,
+    patterns: Vec<Rule<V>>,
     count: usize,
+    case_sensitive: bool,
+    alphabet: usize,
+    // whether fail links are set, so that inserts must link their new nodes
+    built: bool,
 }
 
-impl DeepSizeOf for ACAutomaton {
-    fn deep_size_of_children(&self, _: &mut Context) -> usize {
+impl<V: DeepSizeOf> DeepSizeOf for ACAutomaton<V> {
+    fn deep_size_of_children(&self, context: &mut Context) -> usize {
         self.runtime_memory_size()
+            + self.regex.deep_size_of_children(context)
+            + self.patterns.deep_size_of_children(context)
+    }
+}
+
+impl<V> DomainMatcher<V> for ACAutomaton<V> {
+    fn reverse_insert(&mut self, input_string: &str, match_type: MatchType, value: V) {
+        let rule_id = self.patterns.len() as u32;
+        self.patterns
+            .push(Rule::new(input_string, match_type, value));
+        match match_type {
+            MatchType::Regex(_) => self.regex.insert(input_string, rule_id),
+            _ => self.insert_with_id(input_string, match_type, rule_id),
+        }
+    }
+
+    fn remove(&mut self, pattern: &str, match_type: MatchType) -> bool {
+        let ids = remove_rules(&mut self.patterns, pattern, match_type, self.case_sensitive);
+        let patterns = std::mem::take(&mut self.patterns);
+        for id in ids.iter() {
+            match match_type {
+                MatchType::Regex(_) => self.regex.remove(*id),
+                _ => self.remove_with_id(
+                    pattern,
+                    match_type,
+                    *id,
+                    live_rules(&patterns)
+                        .filter(|(_, rule)| !matches!(rule.match_type, MatchType::Regex(_))),
+                ),
+            }
+        }
+        self.patterns = patterns;
+        !ids.is_empty()
+    }
+
+    fn reverse_query(&self, query_string: &str) -> bool {
+        self.matched_rule_id(query_string).is_some()
+    }
+
+    fn reverse_query_rule(&self, query_string: &str) -> Option<MatchedRule<'_, V>> {
+        self.matched_rule_id(query_string)
+            .map(|id| self.patterns[id as usize].matched(id as usize))
+    }
+
+    fn reverse_query_all(&self, query_string: &str) -> Matches<'_, V> {
+        let query_string = match normalize_query(query_string) {
+            Some(query_string) => query_string,
+            None => return Matches::new(&self.patterns, Vec::new()),
+        };
+        let mut rule_ids = Vec::new();
+        self.collect_rule_ids(query_string, &mut rule_ids);
+        if !self.regex.is_empty() {
+            self.regex.collect_rule_ids(
+                query_string,
+                |id| self.patterns[id as usize].match_type.is_exception(),
+                &mut rule_ids,
+            );
+        }
+        Matches::new(&self.patterns, rule_ids)
+    }
+
+    fn build(&mut self) -> Result<(), BuildError> {
+        let regex = self.regex.build();
+        let alphabet = self.alphabet;
+        let mut queue: VecDeque<usize> = VecDeque::new();
+        for i in 0..alphabet {
+            if self.trie[i].value() != 0 {
+                queue.push_back(self.trie[i].value());
+            }
+        }
+        while let Some(node) = queue.pop_front() {
+            for i in 0..alphabet {
+                let fail = self.fail[node] * alphabet + i;
+                match self.trie[node * alphabet + i] {
+                    EdgeType::TrieEdge(child) => {
+                        self.fail[child] = self.trie[fail].value();
+                        queue.push_back(child);
+                    }
+                    EdgeType::FailEdge(_) => {
+                        self.trie[node * alphabet + i] =
+                            EdgeType::FailEdge(self.trie[fail].value());
+                    }
+                }
+            }
+        }
+        self.built = true;
+        regex
+    }
+
+    fn clear(&mut self) {
+        self.built = false;
+        self.count = 0;
+        self.trie = vec![EdgeType::FailEdge(0); self.alphabet];
+        self.fail = vec![0; 1];
+        self.exists = vec![None; 1];
+        self.substr = vec![None; 1];
+        self.regex.clear();
+        self.patterns.clear();
     }
 }
 
-impl DomainMatcher for ACAutomaton {
-    fn reverse_insert(&mut self, input_string: &str, match_type: MatchType) {
+impl<V> ACAutomaton<V> {
+    /// Creates an automaton with room for `size` trie nodes. Matching ignores
+    /// ASCII case.
+    pub fn new(size: usize) -> ACAutomaton<V> {
+        ACAutomaton::with_case(size, false)
+    }
+
+    /// Creates an automaton that tells ASCII upper and lower case apart.
+    pub fn new_case_sensitive(size: usize) -> ACAutomaton<V> {
+        ACAutomaton::with_case(size, true)
+    }
+
+    pub(crate) fn with_case(size: usize, case_sensitive: bool) -> ACAutomaton<V> {
+        let size = if size == 0 { 1 } else { size };
+        let alphabet = alphabet_size(case_sensitive);
+        ACAutomaton {
+            trie: vec![EdgeType::FailEdge(0); size * alphabet],
+            fail: vec![0; size],
+            exists: vec![None; size],
+            substr: vec![None; size],
+            regex: RegexMatcher::new(case_sensitive),
+            patterns: Vec::new(),
+            count: 0,
+            case_sensitive,
+            alphabet,
+            built: false,
+        }
+    }
+
+    pub fn is_case_sensitive(&self) -> bool {
+        self.case_sensitive
+    }
+
+    /// Inserts a pattern whose rule is owned by the caller, so that a matcher
+    /// embedding the automaton can report its own rule ids.
+    pub(crate) fn insert_with_id(&mut self, input_string: &str, match_type: MatchType, id: u32) {
+        let first_new = self.count + 1;
         let mut node = 0;
-        for c in input_string.chars().rev() {
-            // new node
-            let idx = char2idx(c);
-            if self.trie[node][idx].value() == 0 {
-                self.count += 1;
-                if self.trie.len() < self.count + 1 {
-                    self.trie.push([EdgeType::FailEdge(0); 53]);
-                    self.fail.push(0);
-                    self.exists.push(false.into());
+        for idx in symbols(input_string, self.case_sensitive) {
+            node = self.child(node, idx);
+        }
+        if let MatchType::Domain(_) = match_type {
+            self.mark(
+                node,
+                MatchType::Full(match_type == MatchType::Domain(true)),
+                id,
+            );
+            node = self.child(node, DOT);
+        }
+        // a Full rule takes the node of a name over from a Domain rule for it
+        if let (MatchType::Full(_), Some((_, old))) = (match_type, self.exists[node]) {
+            if takes_key(&self.patterns, old, id) {
+                self.exists[node] = None;
+            }
+        }
+        self.mark(node, match_type, id);
+        if self.built && self.count >= first_new {
+            let path: Vec<usize> = path(input_string, match_type, self.case_sensitive).collect();
+            self.link(&path, first_new);
+        }
+    }
+
+    // Sets the links of the nodes `insert_with_id` added along `path`, from
+    // `first_new` on, and fixes the links of the older nodes they change.
+    //
+    // An old node only needs new links if its string ends with the string of
+    // a new node. `suffixed` holds the old nodes ending with the first `i`
+    // symbols of the path: every node for `i = 0`, then their children along
+    // the path. A node in it whose fail link, or fail edge on the next
+    // symbol, is shorter than the new node of that depth is relinked to it.
+    fn link(&mut self, path: &[usize], first_new: usize) {
+        let alphabet = self.alphabet;
+        let mut nodes = Vec::with_capacity(path.len());
+        let mut node = 0;
+        for idx in path.iter() {
+            node = self.trie[node * alphabet + idx].value();
+            nodes.push(node);
+        }
+        let mut suffixed: Vec<usize> = (0..first_new).collect();
+        let mut in_next = vec![false; first_new];
+        for (idx, new_node) in path.iter().zip(nodes.iter()) {
+            let next: Vec<usize> = suffixed
+                .iter()
+                .filter_map(|node| match self.trie[node * alphabet + idx] {
+                    EdgeType::TrieEdge(child) if child < first_new => Some(child),
+                    _ => None,
+                })
+                .collect();
+            if *new_node >= first_new {
+                // An edge or link to an old node of `next` is longer than
+                // `new_node`; one to a node of this insert is shorter.
+                next.iter().for_each(|node| in_next[*node] = true);
+                let longer = |node: usize| node < first_new && in_next[node];
+                for node in suffixed.iter() {
+                    if let EdgeType::FailEdge(v) = self.trie[node * alphabet + idx] {
+                        if !longer(v) {
+                            self.trie[node * alphabet + idx] = EdgeType::FailEdge(*new_node);
+                        }
+                    }
+                }
+                for node in next.iter() {
+                    if !longer(self.fail[*node]) {
+                        self.fail[*node] = *new_node;
+                    }
                 }
-                self.trie[node][idx] = EdgeType::TrieEdge(self.count);
+                next.iter().for_each(|node| in_next[*node] = false);
             }
+            suffixed = next;
+        }
+        for (depth, node) in nodes.iter().enumerate() {
+            if *node < first_new {
+                continue;
+            }
+            let fail = match depth {
+                0 => 0,
+                _ => self.trie[self.fail[nodes[depth - 1]] * alphabet + path[depth]].value(),
+            };
+            self.fail[*node] = fail;
+            for i in 0..alphabet {
+                if let EdgeType::FailEdge(_) = self.trie[node * alphabet + i] {
+                    self.trie[node * alphabet + i] =
+                        EdgeType::FailEdge(self.trie[fail * alphabet + i].value());
+                }
+            }
+        }
+    }
 
-            node = self.trie[node][idx].value();
+    /// Unmarks the nodes holding rule `id`, inserted as `input_string` with
+    /// `match_type`, and marks each of them again for the first of `rules`
+    /// ending there. `rules` are the other live rules of the automaton, in
+    /// insertion order.
+    pub(crate) fn remove_with_id<'r, W: 'r, I>(
+        &mut self,
+        input_string: &str,
+        match_type: MatchType,
+        id: u32,
+        rules: I,
+    ) where
+        I: IntoIterator<Item = (u32, &'r Rule<W>)>,
+    {
+        let mut freed = Vec::new();
+        for (node, match_type) in self.slots(input_string, match_type) {
+            let slot = self.slot(node, match_type);
+            if slot.is_some_and(|(_, old)| old == id) {
+                *slot = None;
+                freed.push((node, is_substr(match_type)));
+            }
+        }
+        if freed.is_empty() {
+            return;
+        }
+        // Full rules first, as they take the node of a name over from Domain
+        // rules
+        let mut rules: Vec<_> = rules.into_iter().collect();
+        rules.sort_by_key(|(_, rule)| !matches!(rule.match_type, MatchType::Full(_)));
+        for (rule_id, rule) in rules {
+            for (node, match_type) in self.slots(&rule.pattern, rule.match_type) {
+                if freed.contains(&(node, is_substr(match_type))) {
+                    self.mark(node, match_type, rule_id);
+                }
+            }
+        }
+    }
+
+    // The nodes `insert_with_id` marks for a pattern and the match type of
+    // each, or none if the pattern is not in the trie.
+    fn slots(&self, input_string: &str, match_type: MatchType) -> Vec<(usize, MatchType)> {
+        let mut node = 0;
+        for idx in symbols(input_string, self.case_sensitive) {
+            match self.trie[node * self.alphabet + idx] {
+                EdgeType::TrieEdge(child) => node = child,
+                EdgeType::FailEdge(_) => return Vec::new(),
+            }
         }
-        self.exists[node] = match_type;
         match match_type {
-            MatchType::Domain(_) => {
-                self.exists[node] = MatchType::Full(true);
-                let idx = char2idx('.');
-                if self.trie[node][idx].value() == 0 {
-                    self.count += 1;
-                    if self.trie.len() < self.count + 1 {
-                        self.trie.push([EdgeType::FailEdge(0); 53]);
-                        self.fail.push(0);
-                        self.exists.push(false.into());
-                    }
-                    self.trie[node][idx] = EdgeType::TrieEdge(self.count);
+            MatchType::Domain(v) => {
+                let mut slots = vec![(node, MatchType::Full(v))];
+                if let EdgeType::TrieEdge(child) = self.trie[node * self.alphabet + DOT] {
+                    slots.push((child, match_type));
                 }
-                node = self.trie[node][idx].value();
-                self.exists[node] = match_type;
+                slots
             }
-            _ => {}
+            _ => vec![(node, match_type)],
         }
     }
 
-    fn reverse_query(&self, query_string: &str) -> bool {
+    // Follows the trie edge `idx` of `node`, adding a node if there is none.
+    fn child(&mut self, node: usize, idx: usize) -> usize {
+        let edge = node * self.alphabet + idx;
+        if let EdgeType::TrieEdge(v) = self.trie[edge] {
+            return v;
+        }
+        self.count += 1;
+        if self.fail.len() < self.count + 1 {
+            self.trie
+                .resize(self.trie.len() + self.alphabet, EdgeType::FailEdge(0));
+            self.fail.push(0);
+            self.exists.push(None);
+            self.substr.push(None);
+        }
+        self.trie[edge] = EdgeType::TrieEdge(self.count);
+        self.count
+    }
+
+    // The rule of `node` for the stage of `match_type`.
+    fn slot(&mut self, node: usize, match_type: MatchType) -> &mut Option<(MatchType, u32)> {
+        if is_substr(match_type) {
+            &mut self.substr[node]
+        } else {
+            &mut self.exists[node]
+        }
+    }
+
+    // A duplicate pattern keeps the rule id of its first insertion, whether or
+    // not it is an exception.
+    fn mark(&mut self, node: usize, match_type: MatchType, id: u32) {
+        let slot = self.slot(node, match_type);
+        match slot {
+            Some((old, _)) if discriminant(old) == discriminant(&match_type) => {}
+            _ => *slot = Some((match_type, id)),
+        }
+    }
+
+    pub(crate) fn query_rule_id(&self, query_string: &str) -> Option<u32> {
+        Tables::query_rule_id(self, query_string)
+    }
+
+    fn query_with_regex(&self, query_string: &str) -> Option<u32> {
+        match self.query_rule_id(query_string) {
+            Some(id) => Some(id),
+            None if !self.regex.is_empty() => self.regex.query_rule_id(query_string, |id| {
+                self.patterns[id as usize].match_type.is_exception()
+            }),
+            None => None,
+        }
+    }
+
+    fn matched_rule_id(&self, query_string: &str) -> Option<u32> {
+        self.query_with_regex(normalize_query(query_string)?)
+            .filter(|id| !self.patterns[*id as usize].match_type.is_exception())
+    }
+
+    /// Calls `f` with the id of every rule matching `query_string`, exceptions
+    /// included. Unlike `query_rule_id`, SubStr patterns are reported once per
+    /// occurrence.
+    pub(crate) fn for_each_rule_id<F: FnMut(u32)>(&self, query_string: &str, mut f: F) {
         let mut node = 0;
         let mut full_match = true;
-        // 1. the match string is all through trie edge. FULL MATCH or DOMAIN
-        // 2. the match string is through a fail edge. NOT FULL MATCH
-        // 2.1 Through a fail edge, but there exists a valid node. SUBSTR
-        for c in query_string.chars().rev() {
-            node = match self.trie[node][char2idx(c)] {
+        for idx in symbols(query_string, self.case_sensitive) {
+            node = match self.edge(node, idx) {
                 EdgeType::TrieEdge(v) => v,
                 EdgeType::FailEdge(v) => {
                     full_match = false;
                     v
                 }
             };
-            match self.exists[node] {
-                MatchType::SubStr(v) if v => {
-                    return v;
+            if let Some((MatchType::Domain(_), id)) = self.exists[node] {
+                if full_match {
+                    f(id);
                 }
-                MatchType::Domain(v) if full_match => {
-                    return v;
+            }
+            let mut suffix = node;
+            while suffix != 0 {
+                if let Some((MatchType::SubStr(_), id)) = self.substr[suffix] {
+                    f(id);
                 }
-                _ => {}
+                suffix = self.fail[suffix];
             }
         }
-        match self.exists[node] {
-            MatchType::Full(v) => full_match & v,
-            _ => false,
+        if let Some((MatchType::Full(_), id)) = self.exists[node] {
+            if full_match {
+                f(id);
+            }
         }
     }
-    fn build(&mut self) {
-        let mut queue: VecDeque<EdgeType> = VecDeque::new();
-        for i in 0..count_host_valid_character() {
-            if self.trie[0][i].value() != 0 {
-                queue.push_back(self.trie[0][i]);
+
+    /// Appends the id of every rule matching `query_string` to `out`, from the
+    /// most to the least specific: Full and Domain rules by decreasing suffix
+    /// length, then each SubStr rule once, in the order they take precedence.
+    pub(crate) fn collect_rule_ids(&self, query_string: &str, out: &mut Vec<u32>) {
+        let start = out.len();
+        let mut substr: Vec<(bool, u32)> = Vec::new();
+        let mut node = 0;
+        let mut full_match = true;
+        for idx in symbols(query_string, self.case_sensitive) {
+            node = match self.edge(node, idx) {
+                EdgeType::TrieEdge(v) => v,
+                EdgeType::FailEdge(v) => {
+                    full_match = false;
+                    v
+                }
+            };
+            if let Some((MatchType::Domain(_), id)) = self.exists[node] {
+                if full_match {
+                    out.push(id);
+                }
             }
-        }
-        loop {
-            match queue.pop_front() {
-                None => break,
-                Some(node) => {
-                    let node = node.value();
-                    for i in 0..count_host_valid_character() {
-                        if self.trie[node][i].value() != 0 {
-                            self.fail[self.trie[node][i].value()] =
-                                self.trie[self.fail[node]][i].value();
-                            queue.push_back(self.trie[node][i]);
-                        } else {
-                            self.trie[node][i] = match self.trie[self.fail[node]][i] {
-                                EdgeType::TrieEdge(v) => EdgeType::FailEdge(v),
-                                EdgeType::FailEdge(v) => EdgeType::FailEdge(v),
-                            }
-                        }
-                    }
+            let mut suffix = node;
+            while suffix != 0 {
+                if let Some((MatchType::SubStr(v), id)) = self.substr[suffix] {
+                    substr.push((v, id));
                 }
+                suffix = self.fail[suffix];
             }
         }
+        if let Some((MatchType::Full(_), id)) = self.exists[node] {
+            if full_match {
+                out.push(id);
+            }
+        }
+        out[start..].reverse();
+        substr.sort_unstable();
+        substr.dedup();
+        out.extend(substr.into_iter().map(|(_, id)| id));
     }
 
-    fn clear(&mut self) {
-        self.count = 0;
-        self.trie = vec![[EdgeType::FailEdge(0); 53]; 1];
-        self.fail = vec![0; 1];
-        self.exists = vec![false.into(); 1];
+    /// Writes the trie, fail links and node rules. Rules are owned by the
+    /// embedding matcher and are not written.
+    pub(crate) fn write_tables(&self, w: &mut Writer) {
+        let nodes = self.count + 1;
+        w.u32(self.alphabet as u32);
+        w.u32s(
+            self.trie[..nodes * self.alphabet]
+                .iter()
+                .map(|edge| match edge {
+                    EdgeType::TrieEdge(v) => (*v as u32) << 1 | 1,
+                    EdgeType::FailEdge(v) => (*v as u32) << 1,
+                }),
+        );
+        w.u32s(self.fail[..nodes].iter().map(|v| *v as u32));
+        for slots in [&self.exists, &self.substr] {
+            w.u32s(slots[..nodes].iter().map(|e| match e {
+                Some((match_type, _)) => encode_match_type(*match_type),
+                None => NO_RULE,
+            }));
+            w.u32s(slots[..nodes].iter().map(|e| e.map_or(0, |e| e.1)));
+        }
     }
-}
 
-impl ACAutomaton {
-    pub fn new(size: usize) -> ACAutomaton {
-        let size = if size == 0 { 1 } else { size };
-        ACAutomaton {
-            trie: vec![[EdgeType::FailEdge(0); 53]; size],
-            fail: vec![0; size],
-            exists: vec![false.into(); size],
-            count: 0,
+    pub(crate) fn read_tables(r: &mut Reader) -> Result<ACAutomaton<V>, FormatError> {
+        let tables = ACAutomatonRef::read(r)?;
+        let nodes = tables.fail.len();
+        let mut ac = ACAutomaton::with_case(nodes, tables.case_sensitive());
+        for node in 0..nodes {
+            for i in 0..ac.alphabet {
+                ac.trie[node * ac.alphabet + i] = tables.edge(node, i);
+            }
+            ac.fail[node] = tables.fail.get(node) as usize;
+            ac.exists[node] = tables.exists(node);
+            ac.substr[node] = tables.substr(node);
         }
+        ac.count = nodes - 1;
+        ac.built = true;
+        Ok(ac)
     }
 
     pub fn trie_node_count(&self) -> usize {
@@ -247,13 +767,19 @@ impl ACAutomaton {
     }
 
     pub fn shrink_to_fit(&mut self) {
+        self.trie.truncate((self.count + 1) * self.alphabet);
         self.trie.shrink_to_fit();
+        self.exists.truncate(self.count + 1);
         self.exists.shrink_to_fit();
+        self.substr.truncate(self.count + 1);
+        self.substr.shrink_to_fit();
+        self.fail.truncate(self.count + 1);
         self.fail.shrink_to_fit();
     }
 
     pub fn runtime_memory_size(&self) -> usize {
         std::mem::size_of_val(&*self.exists)
+            + std::mem::size_of_val(&*self.substr)
             + std::mem::size_of_val(&*self.fail)
             + std::mem::size_of_val(&*self.trie)
             + std::mem::size_of_val(&self.count)
@@ -264,61 +790,321 @@ impl ACAutomaton {
     }
 }
 
-fn char2idx(c: char) -> usize {
-    match c {
-        'A' | 'a' => 0,
-        'B' | 'b' => 1,
-        'C' | 'c' => 2,
-        'D' | 'd' => 3,
-        'E' | 'e' => 4,
-        'F' | 'f' => 5,
-        'G' | 'g' => 6,
-        'H' | 'h' => 7,
-        'I' | 'i' => 8,
-        'J' | 'j' => 9,
-        'K' | 'k' => 10,
-        'L' | 'l' => 11,
-        'M' | 'm' => 12,
-        'N' | 'n' => 13,
-        'O' | 'o' => 14,
-        'P' | 'p' => 15,
-        'Q' | 'q' => 16,
-        'R' | 'r' => 17,
-        'S' | 's' => 18,
-        'T' | 't' => 19,
-        'U' | 'u' => 20,
-        'V' | 'v' => 21,
-        'W' | 'w' => 22,
-        'X' | 'x' => 23,
-        'Y' | 'y' => 24,
-        'Z' | 'z' => 25,
-        '!' => 26,
-        '$' => 27,
-        '&' => 28,
-        '\'' => 29,
-        '(' => 30,
-        ')' => 31,
-        '*' => 32,
-        '+' => 33,
-        ',' => 34,
-        ';' => 35,
-        '=' => 36,
-        ':' => 37,
-        '%' => 38,
-        '-' => 39,
-        '.' => 40,
-        '_' => 41,
-        '~' => 42,
-        '0' => 43,
-        '1' => 44,
-        '2' => 45,
-        '3' => 46,
-        '4' => 47,
-        '5' => 48,
-        '6' => 49,
-        '7' => 50,
-        '8' => 51,
-        '9' => 52,
-        _ => 0,
+/// A read-only view of the tables written by `ACAutomaton::write_tables`.
+#[derive(Copy, Clone)]
+pub(crate) struct ACAutomatonRef<'a> {
+    alphabet: usize,
+    trie: U32Array<'a>,
+    fail: U32Array<'a>,
+    exists_type: U32Array<'a>,
+    exists_id: U32Array<'a>,
+    substr_type: U32Array<'a>,
+    substr_id: U32Array<'a>,
+}
+
+impl<'a> ACAutomatonRef<'a> {
+    pub(crate) fn read(r: &mut Reader<'a>) -> Result<ACAutomatonRef<'a>, FormatError> {
+        let alphabet = r.u32()? as usize;
+        if alphabet != alphabet_size(false) && alphabet != alphabet_size(true) {
+            return Err(FormatError::Corrupted);
+        }
+        let tables = ACAutomatonRef {
+            alphabet,
+            trie: r.u32s()?,
+            fail: r.u32s()?,
+            exists_type: r.u32s()?,
+            exists_id: r.u32s()?,
+            substr_type: r.u32s()?,
+            substr_id: r.u32s()?,
+        };
+        let nodes = tables.fail.len();
+        if nodes == 0
+            || tables.trie.len() != nodes * alphabet
+            || tables.exists_type.len() != nodes
+            || tables.exists_id.len() != nodes
+            || tables.substr_type.len() != nodes
+            || tables.substr_id.len() != nodes
+            || tables.trie.iter().any(|v| (v >> 1) as usize >= nodes)
+            || tables.fail.iter().any(|v| v as usize >= nodes)
+            || tables
+                .exists_type
+                .iter()
+                .chain(tables.substr_type.iter())
+                .any(|v| v != NO_RULE && decode_match_type(v).is_err())
+        {
+            return Err(FormatError::Corrupted);
+        }
+        Ok(tables)
+    }
+
+    pub(crate) fn empty(&self) -> bool {
+        self.fail.len() == 1
+    }
+
+    pub(crate) fn case_sensitive(&self) -> bool {
+        self.alphabet == alphabet_size(true)
+    }
+
+    pub(crate) fn query_rule_id(&self, query_string: &str) -> Option<u32> {
+        Tables::query_rule_id(self, query_string)
+    }
+
+    /// Rule ids referenced by the trie.
+    pub(crate) fn rule_ids(&self) -> impl Iterator<Item = u32> + 'a {
+        let tables = *self;
+        (0..self.fail.len())
+            .flat_map(move |node| [tables.exists(node), tables.substr(node)])
+            .flatten()
+            .map(|e| e.1)
+    }
+}
+
+/// Read access to the automaton tables, shared by `ACAutomaton` and
+/// `ACAutomatonRef`.
+trait Tables {
+    fn case_sensitive(&self) -> bool;
+    fn edge(&self, node: usize, idx: usize) -> EdgeType;
+    fn fail(&self, node: usize) -> usize;
+    fn exists(&self, node: usize) -> Option<(MatchType, u32)>;
+    fn substr(&self, node: usize) -> Option<(MatchType, u32)>;
+
+    /// Returns the rule deciding `query_string`, which may be an exception.
+    ///
+    /// The longest Domain or Full rule matched along trie edges decides. If
+    /// there is none, SubStr rules found along fail links decide, preferring
+    /// exceptions and then the earliest inserted rule.
+    fn query_rule_id(&self, query_string: &str) -> Option<u32> {
+        let mut node = 0;
+        let mut full_match = true;
+        let mut domain = None;
+        let mut substr: Option<(bool, u32)> = None;
+        // 1. the match string is all through trie edge. FULL MATCH or DOMAIN
+        // 2. the match string is through a fail edge. NOT FULL MATCH
+        // 2.1 Through a fail edge, but there exists a valid node. SUBSTR
+        let case_sensitive = self.case_sensitive();
+        for idx in symbols(query_string, case_sensitive) {
+            node = match self.edge(node, idx) {
+                EdgeType::TrieEdge(v) => v,
+                EdgeType::FailEdge(v) => {
+                    full_match = false;
+                    v
+                }
+            };
+            if let Some((MatchType::Domain(_), id)) = self.exists(node) {
+                if full_match {
+                    domain = Some(id);
+                }
+            }
+            let mut suffix = node;
+            while suffix != 0 {
+                if let Some((MatchType::SubStr(v), id)) = self.substr(suffix) {
+                    if substr.is_none_or(|best| (v, id) < best) {
+                        substr = Some((v, id));
+                    }
+                }
+                suffix = self.fail(suffix);
+            }
+        }
+        if let Some((MatchType::Full(_), id)) = self.exists(node) {
+            if full_match {
+                domain = Some(id);
+            }
+        }
+        domain.or(substr.map(|(_, id)| id))
+    }
+}
+
+impl<V> Tables for ACAutomaton<V> {
+    fn case_sensitive(&self) -> bool {
+        self.case_sensitive
+    }
+
+    #[inline(always)]
+    fn edge(&self, node: usize, idx: usize) -> EdgeType {
+        self.trie[node * self.alphabet + idx]
+    }
+
+    #[inline(always)]
+    fn fail(&self, node: usize) -> usize {
+        self.fail[node]
     }
+
+    #[inline(always)]
+    fn exists(&self, node: usize) -> Option<(MatchType, u32)> {
+        self.exists[node]
+    }
+
+    #[inline(always)]
+    fn substr(&self, node: usize) -> Option<(MatchType, u32)> {
+        self.substr[node]
+    }
+}
+
+impl<'a> Tables for ACAutomatonRef<'a> {
+    fn case_sensitive(&self) -> bool {
+        ACAutomatonRef::case_sensitive(self)
+    }
+
+    #[inline(always)]
+    fn edge(&self, node: usize, idx: usize) -> EdgeType {
+        let v = self.trie.get(node * self.alphabet + idx);
+        if v & 1 == 1 {
+            EdgeType::TrieEdge((v >> 1) as usize)
+        } else {
+            EdgeType::FailEdge((v >> 1) as usize)
+        }
+    }
+
+    #[inline(always)]
+    fn fail(&self, node: usize) -> usize {
+        self.fail.get(node) as usize
+    }
+
+    #[inline(always)]
+    fn exists(&self, node: usize) -> Option<(MatchType, u32)> {
+        match decode_match_type(self.exists_type.get(node)) {
+            Ok(match_type) => Some((match_type, self.exists_id.get(node))),
+            Err(_) => None,
+        }
+    }
+
+    #[inline(always)]
+    fn substr(&self, node: usize) -> Option<(MatchType, u32)> {
+        match decode_match_type(self.substr_type.get(node)) {
+            Ok(match_type) => Some((match_type, self.substr_id.get(node))),
+            Err(_) => None,
+        }
+    }
+}
+
+// Symbols after the 53 host characters. A byte outside the alphabet is
+// written as `ESCAPE` followed by its four base-4 digits, so every byte can be
+// matched without aliasing another one. Uppercase letters get symbols of their
+// own in a case sensitive automaton.
+const ESCAPE: usize = count_host_valid_character();
+const DIGITS: usize = ESCAPE + 1;
+const UPPERCASE: usize = DIGITS + 4;
+// the symbol of '.'
+const DOT: usize = 40;
+
+fn is_substr(match_type: MatchType) -> bool {
+    matches!(match_type, MatchType::SubStr(_))
+}
+
+const fn alphabet_size(case_sensitive: bool) -> usize {
+    if case_sensitive {
+        UPPERCASE + 26
+    } else {
+        UPPERCASE
+    }
+}
+
+/// The symbols of `s` read backwards.
+struct Symbols<'a> {
+    bytes: std::iter::Rev<std::str::Bytes<'a>>,
+    case_sensitive: bool,
+    // digits of an escaped byte still to be returned, last digit first
+    escaped: [usize; 4],
+    pending: usize,
+}
+
+// The symbols `insert_with_id` follows for a pattern.
+fn path(s: &str, match_type: MatchType, case_sensitive: bool) -> impl Iterator<Item = usize> + '_ {
+    let dot = match match_type {
+        MatchType::Domain(_) => Some(DOT),
+        _ => None,
+    };
+    symbols(s, case_sensitive).chain(dot)
+}
+
+fn symbols(s: &str, case_sensitive: bool) -> Symbols<'_> {
+    Symbols {
+        bytes: s.bytes().rev(),
+        case_sensitive,
+        escaped: [0; 4],
+        pending: 0,
+    }
+}
+
+impl<'a> Iterator for Symbols<'a> {
+    type Item = usize;
+
+    #[inline(always)]
+    fn next(&mut self) -> Option<usize> {
+        if self.pending > 0 {
+            self.pending -= 1;
+            return Some(self.escaped[self.pending]);
+        }
+        let b = self.bytes.next()?;
+        match byte2idx(b, self.case_sensitive) {
+            Some(idx) => Some(idx),
+            None => {
+                for (i, digit) in self.escaped.iter_mut().enumerate() {
+                    *digit = DIGITS + ((b >> (2 * i)) & 3) as usize;
+                }
+                self.pending = 4;
+                Some(ESCAPE)
+            }
+        }
+    }
+}
+
+fn byte2idx(b: u8, case_sensitive: bool) -> Option<usize> {
+    Some(match b {
+        b'A'..=b'Z' if case_sensitive => UPPERCASE + (b - b'A') as usize,
+        b'A' | b'a' => 0,
+        b'B' | b'b' => 1,
+        b'C' | b'c' => 2,
+        b'D' | b'd' => 3,
+        b'E' | b'e' => 4,
+        b'F' | b'f' => 5,
+        b'G' | b'g' => 6,
+        b'H' | b'h' => 7,
+        b'I' | b'i' => 8,
+        b'J' | b'j' => 9,
+        b'K' | b'k' => 10,
+        b'L' | b'l' => 11,
+        b'M' | b'm' => 12,
+        b'N' | b'n' => 13,
+        b'O' | b'o' => 14,
+        b'P' | b'p' => 15,
+        b'Q' | b'q' => 16,
+        b'R' | b'r' => 17,
+        b'S' | b's' => 18,
+        b'T' | b't' => 19,
+        b'U' | b'u' => 20,
+        b'V' | b'v' => 21,
+        b'W' | b'w' => 22,
+        b'X' | b'x' => 23,
+        b'Y' | b'y' => 24,
+        b'Z' | b'z' => 25,
+        b'!' => 26,
+        b'$' => 27,
+        b'&' => 28,
+        b'\'' => 29,
+        b'(' => 30,
+        b')' => 31,
+        b'*' => 32,
+        b'+' => 33,
+        b',' => 34,
+        b';' => 35,
+        b'=' => 36,
+        b':' => 37,
+        b'%' => 38,
+        b'-' => 39,
+        b'.' => 40,
+        b'_' => 41,
+        b'~' => 42,
+        b'0' => 43,
+        b'1' => 44,
+        b'2' => 45,
+        b'3' => 46,
+        b'4' => 47,
+        b'5' => 48,
+        b'6' => 49,
+        b'7' => 50,
+        b'8' => 51,
+        b'9' => 52,
+        _ => return None,
+    })
 }
@@ -0,0 +1,246 @@
+//! Queries, inspects, converts and merges the site groups of a geosite.dat file or
+//! of a directory of v2fly domain-list-community lists.
+use clap::{Parser, Subcommand, ValueEnum};
+use deepsize::DeepSizeOf;
+use domain_matcher::ac_automaton::{ACAutomaton, HybridMatcher};
+use domain_matcher::geosite::{self, Domain_Type, LoadError, SiteGroupList, SiteGroupListBuilder};
+use domain_matcher::import::v2fly;
+use domain_matcher::mph::MphMatcher;
+use domain_matcher::{DomainMatcher, MatchType, MatchedRule};
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[derive(Parser)]
+#[command(name = "domain-matcher", version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Prints the rule of a group that matches a domain.
+    Query {
+        domain: String,
+        /// A geosite.dat file, or a directory of domain-list-community lists.
+        #[arg(long, default_value = "data/geosite.dat")]
+        geosite: PathBuf,
+        /// Group tag, optionally with attribute filters, e.g. `google@cn`.
+        /// `@key` keeps the domains carrying the attribute, `@key=N` those
+        /// whose int attribute equals N, and `@!key` the others.
+        #[arg(long)]
+        tag: String,
+        #[arg(long, value_enum, default_value_t = Matcher::Mph)]
+        matcher: Matcher,
+        /// Print every matching rule, exceptions included, not just the
+        /// one that decides.
+        #[arg(long)]
+        all: bool,
+    },
+    /// Lists the groups of a file with their rule counts.
+    ListTags {
+        #[arg(long, default_value = "data/geosite.dat")]
+        geosite: PathBuf,
+    },
+    /// Prints the rule counts per type and the memory a matcher uses.
+    Stats {
+        #[arg(long, default_value = "data/geosite.dat")]
+        geosite: PathBuf,
+        /// Group to count; all groups when left out.
+        #[arg(long)]
+        tag: Option<String>,
+        #[arg(long, value_enum, default_value_t = Matcher::Mph)]
+        matcher: Matcher,
+    },
+    /// Writes a group in the v2fly domain-list-community text format.
+    Convert {
+        #[arg(long, default_value = "data/geosite.dat")]
+        geosite: PathBuf,
+        #[arg(long)]
+        tag: String,
+        /// Output file; standard output when left out.
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Merges the groups of several inputs into one geosite.dat file.
+    Merge {
+        /// geosite.dat files or directories of domain-list-community lists.
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+        #[arg(short, long)]
+        output: PathBuf,
+    },
+}
+
+#[derive(Copy, Clone, ValueEnum)]
+enum Matcher {
+    Ac,
+    Hybrid,
+    Mph,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    match run(cli.command) {
+        Ok(true) => {}
+        // like grep, a query that matches nothing exits with 1
+        Ok(false) => process::exit(1),
+        Err(e) => {
+            eprintln!("domain-matcher: {}", e);
+            process::exit(2);
+        }
+    }
+}
+
+fn run(command: Command) -> Result<bool, Box<dyn Error>> {
+    match command {
+        Command::Query {
+            domain,
+            geosite,
+            tag,
+            matcher,
+            all,
+        } => {
+            let list = load_site_group_list(geosite)?;
+            match matcher {
+                Matcher::Ac => query(ACAutomaton::new(1), &list, &tag, &domain, all),
+                Matcher::Hybrid => query(HybridMatcher::new(1), &list, &tag, &domain, all),
+                Matcher::Mph => query(MphMatcher::new(1), &list, &tag, &domain, all),
+            }
+        }
+        Command::ListTags { geosite } => {
+            let list = load_site_group_list(geosite)?;
+            for group in list.site_group.iter() {
+                println!("{}\t{}", group.tag, group.domain.len());
+            }
+            Ok(true)
+        }
+        Command::Stats {
+            geosite,
+            tag,
+            matcher,
+        } => {
+            let list = load_site_group_list(geosite)?;
+            match matcher {
+                Matcher::Ac => stats(ACAutomaton::new(1), &list, tag.as_deref()),
+                Matcher::Hybrid => stats(HybridMatcher::new(1), &list, tag.as_deref()),
+                Matcher::Mph => stats(MphMatcher::new(1), &list, tag.as_deref()),
+            }
+        }
+        Command::Convert {
+            geosite,
+            tag,
+            output,
+        } => {
+            let list = load_site_group_list(geosite)?;
+            let group = geosite::find_group(&list, &tag)?;
+            let mut out: Box<dyn Write> = match output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(BufWriter::new(io::stdout().lock())),
+            };
+            v2fly::write_list(&mut out, group)?;
+            out.flush()?;
+            Ok(true)
+        }
+        Command::Merge { inputs, output } => {
+            let mut builder = SiteGroupListBuilder::new();
+            for input in inputs {
+                builder.add_site_group_list(load_site_group_list(input)?);
+            }
+            geosite::save_site_group_list(output, &builder.build())?;
+            Ok(true)
+        }
+    }
+}
+
+// Reads a geosite.dat file, or compiles the lists of a directory.
+fn load_site_group_list(path: impl AsRef<Path>) -> Result<SiteGroupList, Box<dyn Error>> {
+    if path.as_ref().is_dir() {
+        Ok(v2fly::Sources::read_dir(path)?.compile()?)
+    } else {
+        Ok(geosite::load_site_group_list(path)?)
+    }
+}
+
+fn query(
+    mut matcher: impl DomainMatcher,
+    list: &SiteGroupList,
+    selector: &str,
+    domain: &str,
+    all: bool,
+) -> Result<bool, Box<dyn Error>> {
+    let (tag, filters) = geosite::parse_selector(selector)?;
+    geosite::insert_filtered_group(&mut matcher, geosite::find_group(list, tag)?, &filters);
+    matcher.build().map_err(LoadError::from)?;
+    if all {
+        let mut matched = false;
+        for rule in matcher.reverse_query_all(domain) {
+            println!("{}", describe(&rule));
+            matched = true;
+        }
+        return Ok(matched);
+    }
+    match matcher.reverse_query_rule(domain) {
+        Some(rule) => {
+            println!("{}", describe(&rule));
+            Ok(true)
+        }
+        None => {
+            println!("no match");
+            Ok(false)
+        }
+    }
+}
+
+// Formats a rule like `convert` writes it, followed by its index.
+fn describe(rule: &MatchedRule<'_>) -> String {
+    let (prefix, included) = match rule.match_type {
+        MatchType::Domain(v) => ("domain", v),
+        MatchType::SubStr(v) => ("keyword", v),
+        MatchType::Full(v) => ("full", v),
+        MatchType::Regex(v) => ("regexp", v),
+    };
+    format!(
+        "{}{}:{}\t(rule {})",
+        if included { "" } else { "exception " },
+        prefix,
+        rule.pattern,
+        rule.index
+    )
+}
+
+fn stats<M: DomainMatcher + DeepSizeOf>(
+    mut matcher: M,
+    list: &SiteGroupList,
+    tag: Option<&str>,
+) -> Result<bool, Box<dyn Error>> {
+    let groups = match tag {
+        Some(tag) => vec![geosite::find_group(list, tag)?],
+        None => list.site_group.iter().collect(),
+    };
+    let mut counts = [0usize; 4];
+    for group in groups {
+        for domain in group.domain.iter() {
+            counts[domain.field_type as usize] += 1;
+        }
+        geosite::insert_group(&mut matcher, group);
+    }
+    matcher.build().map_err(LoadError::from)?;
+    for field_type in [
+        Domain_Type::Plain,
+        Domain_Type::Regex,
+        Domain_Type::Domain,
+        Domain_Type::Full,
+    ] {
+        println!("{:?}\t{}", field_type, counts[field_type as usize]);
+    }
+    println!("Total\t{}", counts.iter().sum::<usize>());
+    println!(
+        "Memory\t{:.2} MiB",
+        matcher.deep_size_of() as f64 / (1024.0 * 1024.0)
+    );
+    Ok(true)
+}
@@ -0,0 +1,280 @@
+//! C API over `MphMatcher`, declared in `include/domain_matcher.h`.
+//!
+//! Every function reports failure through a `DmStatus` and catches panics, so
+//! no unwinding crosses into C. Strings are NUL terminated UTF-8. Enums are
+//! passed in as `uint32_t` and checked, as C may hand over any value.
+//!
+//! The C library is built with
+//! `cargo rustc --release --lib --features ffi --crate-type cdylib`, or
+//! `--crate-type staticlib` for static linking.
+use crate::geosite::{self, LoadError};
+use crate::mph::MphMatcher;
+use crate::{DomainMatcher, MatchType};
+use std::ffi::CStr;
+use std::os::raw::c_char;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::ptr;
+
+/// An `MphMatcher` owned by C code.
+pub struct DmMatcher {
+    inner: MphMatcher,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DmStatus {
+    Ok = 0,
+    NullPointer,
+    InvalidUtf8,
+    /// `build()` failed, see `BuildError`.
+    Build,
+    Io,
+    /// The geosite.dat file is not valid protobuf.
+    InvalidData,
+    UnknownTag,
+    InvalidSelector,
+    Panic,
+    /// A match type outside `DmMatchType`.
+    InvalidMatchType,
+}
+
+impl DmStatus {
+    // Reads a `DmStatus` passed from C as `uint32_t`.
+    fn from_u32(status: u32) -> Option<DmStatus> {
+        Some(match status {
+            0 => DmStatus::Ok,
+            1 => DmStatus::NullPointer,
+            2 => DmStatus::InvalidUtf8,
+            3 => DmStatus::Build,
+            4 => DmStatus::Io,
+            5 => DmStatus::InvalidData,
+            6 => DmStatus::UnknownTag,
+            7 => DmStatus::InvalidSelector,
+            8 => DmStatus::Panic,
+            9 => DmStatus::InvalidMatchType,
+            _ => return None,
+        })
+    }
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DmMatchType {
+    Domain = 0,
+    SubStr,
+    Full,
+    Regex,
+}
+
+impl DmMatchType {
+    // Reads a `DmMatchType` passed from C as `uint32_t`.
+    fn from_u32(match_type: u32) -> Result<DmMatchType, DmStatus> {
+        match match_type {
+            0 => Ok(DmMatchType::Domain),
+            1 => Ok(DmMatchType::SubStr),
+            2 => Ok(DmMatchType::Full),
+            3 => Ok(DmMatchType::Regex),
+            _ => Err(DmStatus::InvalidMatchType),
+        }
+    }
+
+    fn with_exception(self, exception: bool) -> MatchType {
+        match self {
+            DmMatchType::Domain => MatchType::Domain(!exception),
+            DmMatchType::SubStr => MatchType::SubStr(!exception),
+            DmMatchType::Full => MatchType::Full(!exception),
+            DmMatchType::Regex => MatchType::Regex(!exception),
+        }
+    }
+}
+
+impl From<LoadError> for DmStatus {
+    fn from(e: LoadError) -> Self {
+        match e {
+            LoadError::Io(_) => DmStatus::Io,
+            LoadError::Protobuf(_) => DmStatus::InvalidData,
+            LoadError::UnknownTag(_) => DmStatus::UnknownTag,
+            LoadError::InvalidSelector(_) => DmStatus::InvalidSelector,
+            LoadError::Build(_) => DmStatus::Build,
+        }
+    }
+}
+
+// Runs `f`, turning a panic into `DmStatus::Panic`.
+fn guard<F: FnOnce() -> Result<(), DmStatus>>(f: F) -> DmStatus {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(())) => DmStatus::Ok,
+        Ok(Err(status)) => status,
+        Err(_) => DmStatus::Panic,
+    }
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> Result<&'a str, DmStatus> {
+    if s.is_null() {
+        return Err(DmStatus::NullPointer);
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| DmStatus::InvalidUtf8)
+}
+
+/// Returns a static description of the `DmStatus` value `status`.
+#[no_mangle]
+pub extern "C" fn dm_status_str(status: u32) -> *const c_char {
+    let s: &'static [u8] = match DmStatus::from_u32(status) {
+        Some(DmStatus::Ok) => b"ok\0",
+        Some(DmStatus::NullPointer) => b"null pointer argument\0",
+        Some(DmStatus::InvalidUtf8) => b"string is not valid UTF-8\0",
+        Some(DmStatus::Build) => b"failed to build matcher\0",
+        Some(DmStatus::Io) => b"failed to read file\0",
+        Some(DmStatus::InvalidData) => b"dat file has invalid format\0",
+        Some(DmStatus::UnknownTag) => b"no site group with this tag\0",
+        Some(DmStatus::InvalidSelector) => b"invalid group selector\0",
+        Some(DmStatus::Panic) => b"internal error\0",
+        Some(DmStatus::InvalidMatchType) => b"invalid match type\0",
+        None => b"unknown status\0",
+    };
+    s.as_ptr() as *const c_char
+}
+
+/// Creates an empty matcher that ignores ASCII case. Free it with
+/// `dm_matcher_free`.
+#[no_mangle]
+pub extern "C" fn dm_matcher_new() -> *mut DmMatcher {
+    Box::into_raw(Box::new(DmMatcher {
+        inner: MphMatcher::new(1),
+    }))
+}
+
+/// Creates an empty matcher that tells ASCII upper and lower case apart.
+#[no_mangle]
+pub extern "C" fn dm_matcher_new_case_sensitive() -> *mut DmMatcher {
+    Box::into_raw(Box::new(DmMatcher {
+        inner: MphMatcher::new_case_sensitive(1),
+    }))
+}
+
+/// Frees a matcher. Null is ignored.
+///
+/// # Safety
+///
+/// `matcher` must be null or come from this API and not be freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn dm_matcher_free(matcher: *mut DmMatcher) {
+    if !matcher.is_null() {
+        drop(Box::from_raw(matcher));
+    }
+}
+
+/// Inserts a rule of the `DmMatchType` value `match_type`. With `exception`
+/// set, the rule carves matching domains out of the others.
+///
+/// # Safety
+///
+/// `matcher` must be a live matcher and `pattern` a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn dm_matcher_insert(
+    matcher: *mut DmMatcher,
+    pattern: *const c_char,
+    match_type: u32,
+    exception: bool,
+) -> DmStatus {
+    guard(|| {
+        let matcher = matcher.as_mut().ok_or(DmStatus::NullPointer)?;
+        let pattern = str_arg(pattern)?;
+        let match_type = DmMatchType::from_u32(match_type)?.with_exception(exception);
+        matcher.inner.reverse_insert(pattern, match_type, ());
+        Ok(())
+    })
+}
+
+/// Removes the rules inserted as `pattern` with `match_type` and `exception`,
+/// and sets `*removed` to whether there were any.
+///
+/// # Safety
+///
+/// `matcher` must be a live matcher, `pattern` a NUL terminated string and
+/// `removed` null or writable.
+#[no_mangle]
+pub unsafe extern "C" fn dm_matcher_remove(
+    matcher: *mut DmMatcher,
+    pattern: *const c_char,
+    match_type: u32,
+    exception: bool,
+    removed: *mut bool,
+) -> DmStatus {
+    guard(|| {
+        let matcher = matcher.as_mut().ok_or(DmStatus::NullPointer)?;
+        let pattern = str_arg(pattern)?;
+        let match_type = DmMatchType::from_u32(match_type)?.with_exception(exception);
+        let found = matcher.inner.remove(pattern, match_type);
+        if !removed.is_null() {
+            *removed = found;
+        }
+        Ok(())
+    })
+}
+
+/// Prepares the inserted rules for queries.
+///
+/// # Safety
+///
+/// `matcher` must be a live matcher.
+#[no_mangle]
+pub unsafe extern "C" fn dm_matcher_build(matcher: *mut DmMatcher) -> DmStatus {
+    guard(|| {
+        let matcher = matcher.as_mut().ok_or(DmStatus::NullPointer)?;
+        matcher.inner.build().map_err(|_| DmStatus::Build)
+    })
+}
+
+/// Sets `*matched` to whether `domain` matches a rule of a built matcher.
+///
+/// # Safety
+///
+/// `matcher` must be a live matcher, `domain` a NUL terminated string and
+/// `matched` writable. Queries may run on many threads at once, but not
+/// together with a call that changes the matcher.
+#[no_mangle]
+pub unsafe extern "C" fn dm_matcher_query(
+    matcher: *const DmMatcher,
+    domain: *const c_char,
+    matched: *mut bool,
+) -> DmStatus {
+    guard(|| {
+        let matcher = matcher.as_ref().ok_or(DmStatus::NullPointer)?;
+        let domain = str_arg(domain)?;
+        if matched.is_null() {
+            return Err(DmStatus::NullPointer);
+        }
+        *matched = matcher.inner.reverse_query(domain);
+        Ok(())
+    })
+}
+
+/// Loads the group selected by `selector`, e.g. `google@cn`, from the
+/// geosite.dat file at `path` into a new built matcher stored in `*matcher`.
+/// On error `*matcher` is set to null.
+///
+/// # Safety
+///
+/// `path` and `selector` must be NUL terminated strings and `matcher`
+/// writable.
+#[no_mangle]
+pub unsafe extern "C" fn dm_matcher_load_geosite(
+    path: *const c_char,
+    selector: *const c_char,
+    matcher: *mut *mut DmMatcher,
+) -> DmStatus {
+    if matcher.is_null() {
+        return DmStatus::NullPointer;
+    }
+    *matcher = ptr::null_mut();
+    guard(|| {
+        let path = str_arg(path)?;
+        let selector = str_arg(selector)?;
+        let inner = geosite::load_group(path, selector)?;
+        *matcher = Box::into_raw(Box::new(DmMatcher { inner }));
+        Ok(())
+    })
+}
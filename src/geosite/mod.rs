@@ -0,0 +1,321 @@
+// `geosite.rs` is generated from `src/geosite.proto` by `build.rs`.
+#[allow(clippy::all, warnings)]
+mod geosite {
+    include!(concat!(env!("OUT_DIR"), "/geosite.rs"));
+}
+
+pub use self::geosite::*;
+
+use crate::mph::MphMatcher;
+use crate::{BuildError, DomainMatcher, MatchType};
+use protobuf::{Message, ProtobufError};
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum LoadError {
+    Io(io::Error),
+    Protobuf(ProtobufError),
+    UnknownTag(String),
+    InvalidSelector(String),
+    Build(BuildError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LoadError::Io(e) => write!(f, "failed to read dat file: {}", e),
+            LoadError::Protobuf(e) => write!(f, "dat file has invalid format: {}", e),
+            LoadError::UnknownTag(tag) => write!(f, "no site group tagged {}", tag),
+            LoadError::InvalidSelector(s) => write!(f, "invalid group selector {}", s),
+            LoadError::Build(e) => write!(f, "failed to build matcher: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LoadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            LoadError::Io(e) => Some(e),
+            LoadError::Protobuf(e) => Some(e),
+            LoadError::Build(e) => Some(e),
+            LoadError::UnknownTag(_) | LoadError::InvalidSelector(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for LoadError {
+    fn from(e: io::Error) -> Self {
+        LoadError::Io(e)
+    }
+}
+
+impl From<ProtobufError> for LoadError {
+    fn from(e: ProtobufError) -> Self {
+        LoadError::Protobuf(e)
+    }
+}
+
+impl From<BuildError> for LoadError {
+    fn from(e: BuildError) -> Self {
+        LoadError::Build(e)
+    }
+}
+
+impl From<Domain_Type> for MatchType {
+    fn from(v: Domain_Type) -> Self {
+        match v {
+            Domain_Type::Plain => MatchType::SubStr(true),
+            Domain_Type::Regex => MatchType::Regex(true),
+            Domain_Type::Domain => MatchType::Domain(true),
+            Domain_Type::Full => MatchType::Full(true),
+        }
+    }
+}
+
+/// Formats a domain as a line of the v2fly domain-list-community text
+/// format, e.g. `domain:google.cn @cn`. An int attribute is written as
+/// `@key=N`, like in a group selector.
+impl fmt::Display for Domain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let prefix = match self.field_type {
+            Domain_Type::Plain => "keyword",
+            Domain_Type::Regex => "regexp",
+            Domain_Type::Domain => "domain",
+            Domain_Type::Full => "full",
+        };
+        write!(f, "{}:{}", prefix, self.value)?;
+        for attr in self.attribute.iter() {
+            match attr.typed_value {
+                Some(Domain_Attribute_oneof_typed_value::int_value(v)) => {
+                    write!(f, " @{}={}", attr.key, v)?
+                }
+                Some(Domain_Attribute_oneof_typed_value::bool_value(false)) => {}
+                _ => write!(f, " @{}", attr.key)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// An attribute filter from a group selector such as `google@cn`.
+///
+/// `@key` keeps domains carrying the attribute, whatever its value, as v2ray
+/// does. `@key=N`, which v2ray does not have, keeps domains whose int
+/// attribute equals `N`. A leading `!` (`@!key`, `@!key=N`) keeps the domains
+/// that do not match instead.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AttributeFilter {
+    pub key: String,
+    pub value: Option<i64>,
+    pub negated: bool,
+}
+
+impl AttributeFilter {
+    pub fn matches(&self, domain: &Domain) -> bool {
+        let found = domain.get_attribute().iter().any(|attr| {
+            attr.get_key().eq_ignore_ascii_case(&self.key)
+                && match (&attr.typed_value, self.value) {
+                    (_, None) => true,
+                    (Some(Domain_Attribute_oneof_typed_value::int_value(v)), Some(n)) => *v == n,
+                    (_, Some(_)) => false,
+                }
+        });
+        found != self.negated
+    }
+}
+
+/// Splits a selector such as `category-ads-all@!cn` into the group tag and
+/// its attribute filters.
+pub fn parse_selector(selector: &str) -> Result<(&str, Vec<AttributeFilter>), LoadError> {
+    let mut parts = selector.split('@');
+    let tag = parts.next().unwrap_or_default();
+    let mut filters = Vec::new();
+    for part in parts {
+        let (negated, part) = match part.strip_prefix('!') {
+            Some(part) => (true, part),
+            None => (false, part),
+        };
+        let (key, value) = match part.find('=') {
+            Some(pos) => match part[pos + 1..].parse::<i64>() {
+                Ok(v) => (&part[..pos], Some(v)),
+                Err(_) => return Err(LoadError::InvalidSelector(selector.to_string())),
+            },
+            None => (part, None),
+        };
+        if key.is_empty() {
+            return Err(LoadError::InvalidSelector(selector.to_string()));
+        }
+        filters.push(AttributeFilter {
+            key: key.to_string(),
+            value,
+            negated,
+        });
+    }
+    if tag.is_empty() {
+        return Err(LoadError::InvalidSelector(selector.to_string()));
+    }
+    Ok((tag, filters))
+}
+
+/// Reads and parses a geosite.dat file.
+pub fn load_site_group_list<P: AsRef<Path>>(path: P) -> Result<SiteGroupList, LoadError> {
+    let mut f = File::open(path)?;
+    Ok(Message::parse_from_reader(&mut f)?)
+}
+
+/// Collects groups into a `SiteGroupList` to write as a geosite.dat file.
+/// Groups with the same tag, ignoring ASCII case, are merged into one.
+#[derive(Clone, Debug, Default)]
+pub struct SiteGroupListBuilder {
+    groups: BTreeMap<String, Vec<Domain>>,
+}
+
+impl SiteGroupListBuilder {
+    pub fn new() -> SiteGroupListBuilder {
+        SiteGroupListBuilder::default()
+    }
+
+    /// Adds `domains` to the group tagged `tag`.
+    pub fn add_domains(
+        &mut self,
+        tag: &str,
+        domains: impl IntoIterator<Item = Domain>,
+    ) -> &mut SiteGroupListBuilder {
+        self.groups
+            .entry(tag.to_ascii_uppercase())
+            .or_default()
+            .extend(domains);
+        self
+    }
+
+    pub fn add_group(&mut self, group: SiteGroup) -> &mut SiteGroupListBuilder {
+        let SiteGroup { tag, domain, .. } = group;
+        self.add_domains(&tag, domain)
+    }
+
+    /// Adds every group of `site_group_list`, such as one read by
+    /// `load_site_group_list` or compiled by `import::v2fly`.
+    pub fn add_site_group_list(
+        &mut self,
+        site_group_list: SiteGroupList,
+    ) -> &mut SiteGroupListBuilder {
+        for group in site_group_list.site_group {
+            self.add_group(group);
+        }
+        self
+    }
+
+    /// Returns the groups sorted by their upper case tag, which v2ray and
+    /// Xray look up, each with its domains sorted by type, value and
+    /// attributes and without duplicates.
+    pub fn build(&self) -> SiteGroupList {
+        let mut site_group_list = SiteGroupList::new();
+        for (tag, domains) in self.groups.iter() {
+            let mut domains = domains.clone();
+            for domain in domains.iter_mut() {
+                let mut attrs = domain.take_attribute().into_vec();
+                attrs.sort_by(|a, b| attribute_key(a).cmp(&attribute_key(b)));
+                attrs.dedup_by(|a, b| attribute_key(a) == attribute_key(b));
+                domain.set_attribute(attrs.into());
+            }
+            domains.sort_by(compare_domains);
+            domains.dedup_by(|a, b| compare_domains(a, b) == Ordering::Equal);
+            let mut group = SiteGroup::new();
+            group.set_tag(tag.clone());
+            group.set_domain(domains.into());
+            site_group_list.mut_site_group().push(group);
+        }
+        site_group_list
+    }
+}
+
+fn attribute_key(attr: &Domain_Attribute) -> (&str, u8, i64) {
+    match attr.typed_value {
+        None => (attr.get_key(), 0, 0),
+        Some(Domain_Attribute_oneof_typed_value::bool_value(v)) => (attr.get_key(), 1, v as i64),
+        Some(Domain_Attribute_oneof_typed_value::int_value(v)) => (attr.get_key(), 2, v),
+    }
+}
+
+fn compare_domains(a: &Domain, b: &Domain) -> Ordering {
+    (a.field_type as i32)
+        .cmp(&(b.field_type as i32))
+        .then_with(|| a.value.cmp(&b.value))
+        .then_with(|| {
+            a.attribute
+                .iter()
+                .map(attribute_key)
+                .cmp(b.attribute.iter().map(attribute_key))
+        })
+}
+
+/// Serializes `site_group_list` in the geosite.dat format.
+pub fn write_site_group_list<W: Write>(
+    writer: &mut W,
+    site_group_list: &SiteGroupList,
+) -> io::Result<()> {
+    site_group_list
+        .write_to_writer(writer)
+        .map_err(|e| match e {
+            ProtobufError::IoError(e) => e,
+            e => io::Error::new(io::ErrorKind::InvalidData, e),
+        })
+}
+
+/// Writes `site_group_list` to a geosite.dat file at `path`.
+pub fn save_site_group_list<P: AsRef<Path>>(
+    path: P,
+    site_group_list: &SiteGroupList,
+) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_site_group_list(&mut writer, site_group_list)?;
+    writer.flush()
+}
+
+/// Finds the group tagged `tag`, ignoring ASCII case.
+pub fn find_group<'a>(
+    site_group_list: &'a SiteGroupList,
+    tag: &str,
+) -> Result<&'a SiteGroup, LoadError> {
+    site_group_list
+        .site_group
+        .iter()
+        .find(|group| group.tag.eq_ignore_ascii_case(tag))
+        .ok_or_else(|| LoadError::UnknownTag(tag.to_string()))
+}
+
+/// Inserts every domain of `group` into `matcher`. The caller still has to
+/// call `build()`.
+pub fn insert_group(matcher: &mut impl DomainMatcher, group: &SiteGroup) {
+    insert_filtered_group(matcher, group, &[]);
+}
+
+/// Inserts the domains of `group` that pass all `filters` into `matcher`.
+pub fn insert_filtered_group(
+    matcher: &mut impl DomainMatcher,
+    group: &SiteGroup,
+    filters: &[AttributeFilter],
+) {
+    for domain in group.domain.iter() {
+        if filters.iter().all(|filter| filter.matches(domain)) {
+            matcher.reverse_insert(domain.get_value(), domain.field_type.into(), ());
+        }
+    }
+}
+
+/// Loads the group selected by `selector` from the geosite.dat file at `path`
+/// into a built `MphMatcher`. The selector is a tag optionally followed by
+/// attribute filters, e.g. `google@cn`.
+pub fn load_group<P: AsRef<Path>>(path: P, selector: &str) -> Result<MphMatcher, LoadError> {
+    let (tag, filters) = parse_selector(selector)?;
+    let site_group_list = load_site_group_list(path)?;
+    let mut matcher = MphMatcher::new(1);
+    insert_filtered_group(&mut matcher, find_group(&site_group_list, tag)?, &filters);
+    matcher.build()?;
+    Ok(matcher)
+}
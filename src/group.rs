@@ -0,0 +1,235 @@
+#[cfg(feature = "pb")]
+use crate::geosite;
+use crate::mph::MphMatcher;
+use crate::{BuildError, DomainMatcher, MatchType};
+use deepsize::DeepSizeOf;
+use std::collections::HashMap;
+
+/// A compact set of group ids.
+#[derive(Clone, Default, Debug, PartialEq, Eq, DeepSizeOf)]
+pub struct TagSet {
+    words: Vec<u64>,
+}
+
+impl TagSet {
+    pub fn new() -> TagSet {
+        TagSet { words: Vec::new() }
+    }
+
+    pub fn insert(&mut self, id: usize) {
+        let word = id / 64;
+        if self.words.len() <= word {
+            self.words.resize(word + 1, 0);
+        }
+        self.words[word] |= 1 << (id % 64);
+    }
+
+    pub fn contains(&self, id: usize) -> bool {
+        match self.words.get(id / 64) {
+            Some(w) => w & (1 << (id % 64)) != 0,
+            None => false,
+        }
+    }
+
+    pub fn union_with(&mut self, other: &TagSet) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (w, o) in self.words.iter_mut().zip(other.words.iter()) {
+            *w |= o;
+        }
+    }
+
+    /// Adds the ids of `other` that `except` does not hold.
+    pub fn union_except(&mut self, other: &TagSet, except: &TagSet) {
+        if self.words.len() < other.words.len() {
+            self.words.resize(other.words.len(), 0);
+        }
+        for (i, (w, o)) in self.words.iter_mut().zip(other.words.iter()).enumerate() {
+            *w |= o & !except.words.get(i).copied().unwrap_or(0);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.words.iter().all(|w| *w == 0)
+    }
+
+    /// Iterates over the ids in ascending order.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.words.iter().enumerate().flat_map(|(i, w)| {
+            (0..64)
+                .filter(move |bit| w & (1 << bit) != 0)
+                .map(move |bit| i * 64 + bit)
+        })
+    }
+}
+
+// The groups that contain a rule, and the groups that contain it as an
+// exception.
+#[derive(Clone, Default, DeepSizeOf)]
+struct RuleTags {
+    include: TagSet,
+    except: TagSet,
+}
+
+/// Matches a domain against many groups at once and reports every group it
+/// belongs to.
+///
+/// All groups share one `MphMatcher` whose rules carry the sets of groups that
+/// contain them, as rules and as exceptions, so a query costs one reverse
+/// scan regardless of the number of groups. Within each group the most
+/// specific matching rule decides, so an exception takes its groups out of the
+/// result.
+#[derive(DeepSizeOf)]
+pub struct GroupMatcher {
+    tags: Vec<String>,
+    matcher: MphMatcher<RuleTags>,
+    has_exceptions: bool,
+}
+
+impl GroupMatcher {
+    /// Builds the matcher from `(tag, rules)` pairs. A tag may appear more
+    /// than once; its rules are merged.
+    pub fn new<G, T, R, P>(groups: G) -> Result<GroupMatcher, BuildError>
+    where
+        G: IntoIterator<Item = (T, R)>,
+        T: AsRef<str>,
+        R: IntoIterator<Item = (P, MatchType)>,
+        P: AsRef<str>,
+    {
+        let mut tags: Vec<String> = Vec::new();
+        let mut rules: Vec<(String, MatchType, RuleTags)> = Vec::new();
+        let mut rule_index: HashMap<(String, MatchType), usize> = HashMap::new();
+        for (tag, group) in groups {
+            let tag_id = match tags.iter().position(|t| t == tag.as_ref()) {
+                Some(id) => id,
+                None => {
+                    tags.push(tag.as_ref().to_string());
+                    tags.len() - 1
+                }
+            };
+            for (pattern, match_type) in group {
+                // the matcher folds the case of every other type, so rules
+                // differing only in case share its key
+                let pattern = match match_type {
+                    MatchType::Regex(_) => pattern.as_ref().to_string(),
+                    _ => pattern.as_ref().to_ascii_lowercase(),
+                };
+                // a rule and an exception with the same pattern share the
+                // key, so they are merged and the exception kept in the tags
+                let key = (pattern, included(match_type));
+                let idx = *rule_index.entry(key).or_insert_with_key(|key| {
+                    rules.push((key.0.clone(), key.1, RuleTags::default()));
+                    rules.len() - 1
+                });
+                if match_type.is_exception() {
+                    rules[idx].2.except.insert(tag_id);
+                } else {
+                    rules[idx].2.include.insert(tag_id);
+                }
+            }
+        }
+        // A Domain rule and a Full rule with the same pattern share the key of
+        // the bare domain, which the matcher gives to the Full rule, so the
+        // Full rule carries the Domain groups it does not decide itself.
+        for idx in 0..rules.len() {
+            if let MatchType::Domain(_) = rules[idx].1 {
+                let full_key = (rules[idx].0.clone(), MatchType::Full(true));
+                if let Some(&full) = rule_index.get(&full_key) {
+                    let domain_tags = rules[idx].2.clone();
+                    let full_tags = &mut rules[full].2;
+                    let mut decided = full_tags.include.clone();
+                    decided.union_with(&full_tags.except);
+                    full_tags
+                        .include
+                        .union_except(&domain_tags.include, &decided);
+                    full_tags.except.union_except(&domain_tags.except, &decided);
+                }
+            }
+        }
+        let has_exceptions = rules.iter().any(|(_, _, tags)| !tags.except.is_empty());
+        let mut matcher = MphMatcher::new(1);
+        rules.sort_by_key(|(_, match_type, _)| match match_type {
+            MatchType::Full(_) => 0,
+            MatchType::Domain(_) => 1,
+            MatchType::SubStr(_) => 2,
+            MatchType::Regex(_) => 3,
+        });
+        for (pattern, match_type, tag_set) in rules {
+            matcher.reverse_insert(&pattern, match_type, tag_set);
+        }
+        matcher.build()?;
+        Ok(GroupMatcher {
+            tags,
+            matcher,
+            has_exceptions,
+        })
+    }
+
+    /// Builds the matcher from every group of a geosite.dat list.
+    #[cfg(feature = "pb")]
+    pub fn from_site_group_list(
+        site_group_list: &geosite::SiteGroupList,
+    ) -> Result<GroupMatcher, BuildError> {
+        GroupMatcher::new(site_group_list.site_group.iter().map(|group| {
+            (
+                group.tag.as_str(),
+                group
+                    .domain
+                    .iter()
+                    .map(|domain| (domain.get_value(), domain.field_type.into())),
+            )
+        }))
+    }
+
+    /// Returns the ids of all groups containing `query_string`.
+    pub fn reverse_query(&self, query_string: &str) -> TagSet {
+        let mut tag_set = TagSet::new();
+        if !self.has_exceptions {
+            self.matcher
+                .for_each_match(query_string, |rule| tag_set.union_with(&rule.value.include));
+            return tag_set;
+        }
+        // the groups of a rule are decided by it unless a more specific rule
+        // already decided them, and an exception wins over a rule of the same
+        // group with the same pattern
+        let mut decided = TagSet::new();
+        for rule in self.matcher.reverse_query_all(query_string) {
+            decided.union_with(&rule.value.except);
+            tag_set.union_except(&rule.value.include, &decided);
+            decided.union_with(&rule.value.include);
+        }
+        tag_set
+    }
+
+    /// Returns the tags of all groups containing `query_string`.
+    pub fn reverse_query_tags(&self, query_string: &str) -> Vec<&str> {
+        self.reverse_query(query_string)
+            .iter()
+            .map(|id| self.tags[id].as_str())
+            .collect()
+    }
+
+    pub fn tag(&self, id: usize) -> &str {
+        &self.tags[id]
+    }
+
+    /// Looks a tag up case-insensitively.
+    pub fn tag_id(&self, tag: &str) -> Option<usize> {
+        self.tags.iter().position(|t| t.eq_ignore_ascii_case(tag))
+    }
+
+    pub fn tag_count(&self) -> usize {
+        self.tags.len()
+    }
+}
+
+// Returns the match type of the same kind that is not an exception.
+fn included(match_type: MatchType) -> MatchType {
+    match match_type {
+        MatchType::Domain(_) => MatchType::Domain(true),
+        MatchType::SubStr(_) => MatchType::SubStr(true),
+        MatchType::Full(_) => MatchType::Full(true),
+        MatchType::Regex(_) => MatchType::Regex(true),
+    }
+}
@@ -0,0 +1,100 @@
+//! IDNA (UTS #46) normalization of rules and queries.
+use crate::{BuildError, DomainMatcher, MatchType, MatchedRule, Matches};
+use std::borrow::Cow;
+
+/// Converts a domain name to its ASCII form, e.g. `bücher.de` to
+/// `xn--bcher-kva.de`. ASCII input is returned as is without allocating, and
+/// so is input that is not a valid internationalized domain name.
+pub fn to_ascii(domain: &str) -> Cow<'_, str> {
+    if domain.is_ascii() {
+        return Cow::Borrowed(domain);
+    }
+    match idna::domain_to_ascii(domain) {
+        Ok(ascii) => Cow::Owned(ascii),
+        Err(_) => Cow::Borrowed(domain),
+    }
+}
+
+// Converts each label of a SubStr pattern, e.g. `.中国` to `.xn--fiqs8s`.
+fn keyword_to_ascii(keyword: &str) -> Cow<'_, str> {
+    if keyword.is_ascii() {
+        return Cow::Borrowed(keyword);
+    }
+    let labels: Vec<Cow<'_, str>> = keyword.split('.').map(to_ascii).collect();
+    Cow::Owned(labels.join("."))
+}
+
+/// Puts a matcher behind IDNA normalization, so that `bücher.de` and
+/// `xn--bcher-kva.de` hit the same rule.
+///
+/// Full and Domain patterns are stored in their ASCII form, which is also the
+/// `pattern` reported for them. A SubStr pattern is converted label by label,
+/// so `中国` matches `中国.cn`; a keyword that is only part of a non-ASCII
+/// label, such as `国`, cannot match, as punycode encodes whole labels. Regex
+/// patterns are kept as they are and, like every query, see the ASCII form,
+/// so a regex for non-ASCII names must be written in punycode. Every query is
+/// converted before it reaches the matcher, so the existing ASCII alphabet
+/// and byte hashes only ever see ASCII names.
+pub struct IdnaMatcher<M> {
+    inner: M,
+}
+
+impl<M> IdnaMatcher<M> {
+    pub fn new(inner: M) -> IdnaMatcher<M> {
+        IdnaMatcher { inner }
+    }
+
+    pub fn get_ref(&self) -> &M {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+}
+
+impl<V, M: DomainMatcher<V>> DomainMatcher<V> for IdnaMatcher<M> {
+    fn reverse_insert(&mut self, input_string: &str, match_type: MatchType, value: V) {
+        match match_type {
+            MatchType::Domain(_) | MatchType::Full(_) => {
+                self.inner
+                    .reverse_insert(&to_ascii(input_string), match_type, value)
+            }
+            MatchType::SubStr(_) => {
+                self.inner
+                    .reverse_insert(&keyword_to_ascii(input_string), match_type, value)
+            }
+            MatchType::Regex(_) => self.inner.reverse_insert(input_string, match_type, value),
+        }
+    }
+
+    fn remove(&mut self, pattern: &str, match_type: MatchType) -> bool {
+        match match_type {
+            MatchType::Domain(_) | MatchType::Full(_) => {
+                self.inner.remove(&to_ascii(pattern), match_type)
+            }
+            MatchType::SubStr(_) => self.inner.remove(&keyword_to_ascii(pattern), match_type),
+            MatchType::Regex(_) => self.inner.remove(pattern, match_type),
+        }
+    }
+
+    fn reverse_query(&self, query_string: &str) -> bool {
+        self.inner.reverse_query(&to_ascii(query_string))
+    }
+
+    fn reverse_query_rule(&self, query_string: &str) -> Option<MatchedRule<'_, V>> {
+        self.inner.reverse_query_rule(&to_ascii(query_string))
+    }
+
+    fn reverse_query_all(&self, query_string: &str) -> Matches<'_, V> {
+        self.inner.reverse_query_all(&to_ascii(query_string))
+    }
+
+    fn build(&mut self) -> Result<(), BuildError> {
+        self.inner.build()
+    }
+
+    fn clear(&mut self) {
+        self.inner.clear()
+    }
+}
@@ -0,0 +1,250 @@
+//! The DNS subset of Adblock Plus and AdGuard filter lists.
+//!
+//! * `||example.com^` is a Domain rule, `|example.com^` and
+//!   `|https://example.com^` are Full rules, and a bare `example` a SubStr
+//!   rule.
+//! * `/^ad[0-9]+\./`, names with a `*`, such as `||ads*.example.com^`, and
+//!   names without a closing `^`, such as `||example.com`, which also matches
+//!   `example.company`, are Regex rules.
+//! * An `@@` prefix makes an exception, and a `$important` suffix lets a rule
+//!   override the exceptions without it.
+//!
+//! `!` comments and `[Adblock Plus 2.0]` headers are ignored. Cosmetic rules,
+//! rules on a URL path and rules with any other `$` option are skipped, each
+//! with its `SkipReason`.
+use super::{ImportReport, SkipReason};
+use crate::{DomainMatcher, MatchType};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// Separators of element hiding, CSS, scriptlet and HTML filtering rules.
+const COSMETIC_MARKERS: [&str; 10] = [
+    "##", "#@#", "#?#", "#@?#", "#$#", "#@$#", "#%#", "#@%#", "$$", "$@$",
+];
+
+/// A network rule of a filter list, as far as a DNS lookup can use it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FilterRule {
+    pub pattern: String,
+    /// Exceptions (`@@`) are inserted with `false`.
+    pub match_type: MatchType,
+    pub important: bool,
+}
+
+/// Parses one line of a filter list. Returns `Ok(None)` for a blank line, a
+/// comment or a header.
+pub fn parse_line(line: &str) -> Result<Option<FilterRule>, SkipReason> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('!') || line.starts_with('[') {
+        return Ok(None);
+    }
+    if COSMETIC_MARKERS.iter().any(|marker| line.contains(marker)) {
+        return Err(SkipReason::Cosmetic);
+    }
+    let (included, rule) = match line.strip_prefix("@@") {
+        Some(rule) => (false, rule),
+        None => (true, line),
+    };
+    // `$` may also appear inside a regex rule, which takes no options here.
+    let (rule, important) = match rule.rfind('$') {
+        Some(pos) if !(rule.starts_with('/') && rule.ends_with('/')) => match &rule[pos + 1..] {
+            "important" => (&rule[..pos], true),
+            _ => return Err(SkipReason::Unsupported),
+        },
+        _ => (rule, false),
+    };
+    let (pattern, match_type) = parse_pattern(rule)?;
+    Ok(Some(FilterRule {
+        pattern,
+        match_type: match match_type {
+            MatchType::Domain(_) => MatchType::Domain(included),
+            MatchType::SubStr(_) => MatchType::SubStr(included),
+            MatchType::Full(_) => MatchType::Full(included),
+            MatchType::Regex(_) => MatchType::Regex(included),
+        },
+        important,
+    }))
+}
+
+// Turns a rule without its `@@` and options into a pattern.
+fn parse_pattern(rule: &str) -> Result<(String, MatchType), SkipReason> {
+    if rule.len() > 2 && rule.starts_with('/') && rule.ends_with('/') {
+        return Ok((rule[1..rule.len() - 1].to_string(), MatchType::Regex(true)));
+    }
+    // such as a hosts file line, which `hosts` reads
+    if rule.contains(char::is_whitespace) {
+        return Err(SkipReason::Unsupported);
+    }
+    let (anchor, rest) = if let Some(rest) = rule.strip_prefix("||") {
+        (Anchor::Domain, rest)
+    } else if let Some(rest) = rule.strip_prefix('|') {
+        let rest = rest
+            .strip_prefix("http://")
+            .or_else(|| rest.strip_prefix("https://"))
+            .unwrap_or(rest);
+        (Anchor::Start, rest)
+    } else {
+        (Anchor::None, rule)
+    };
+    // The host ends at the first separator; a path after it is out of reach.
+    let end = rest.find(['^', '/', ':', '?', '|']).unwrap_or(rest.len());
+    let (host, tail) = rest.split_at(end);
+    let tail = tail.trim_start_matches(['^', '/', '|']);
+    if !tail.is_empty() {
+        return Err(SkipReason::UrlPath);
+    }
+    let terminated = end < rest.len();
+    let anchored = !matches!(anchor, Anchor::None);
+    if host.is_empty()
+        || (anchored && host.split('.').any(str::is_empty))
+        || !host
+            .chars()
+            .all(|c| c.is_alphanumeric() || matches!(c, '-' | '_' | '.' | '*'))
+    {
+        return Err(SkipReason::Malformed);
+    }
+    if host.contains('*') {
+        return Ok((anchor.regex(host, terminated), MatchType::Regex(true)));
+    }
+    match (anchor, terminated) {
+        (Anchor::Domain, true) => Ok((host.to_string(), MatchType::Domain(true))),
+        (Anchor::Start, true) => Ok((host.to_string(), MatchType::Full(true))),
+        (Anchor::None, false) => Ok((host.to_string(), MatchType::SubStr(true))),
+        _ => Ok((anchor.regex(host, terminated), MatchType::Regex(true))),
+    }
+}
+
+#[derive(Copy, Clone)]
+enum Anchor {
+    /// `||`: the start of the host or of one of its labels.
+    Domain,
+    /// `|`: the start of the host.
+    Start,
+    None,
+}
+
+impl Anchor {
+    // A regex for `host`, in which `*` stands for any run of characters. A
+    // terminated host must reach the end of the name.
+    fn regex(self, host: &str, terminated: bool) -> String {
+        let mut re = String::from(match self {
+            Anchor::Domain => "(^|\\.)",
+            Anchor::Start => "^",
+            Anchor::None => "",
+        });
+        let parts: Vec<String> = host.split('*').map(regex::escape).collect();
+        re.push_str(&parts.join(".*"));
+        if terminated {
+            re.push('$');
+        }
+        re
+    }
+}
+
+/// Inserts the rules of a filter list into `matcher`, each with a clone of
+/// `value`, and reports the lines it skipped.
+///
+/// An `$important` rule beats the exceptions without the option that would
+/// otherwise decide some of its names; those exceptions are skipped as
+/// `Overridden`. Likewise an important exception overrides every blocking
+/// rule for its names. This only looks at Domain and Full rules.
+pub fn insert_rules<V: Clone>(
+    matcher: &mut impl DomainMatcher<V>,
+    text: &str,
+    value: V,
+) -> ImportReport {
+    let mut report = ImportReport::default();
+    let mut rules = Vec::new();
+    for (idx, line) in text.lines().enumerate() {
+        match parse_line(line) {
+            Ok(Some(rule)) => rules.push((idx + 1, line.trim(), rule)),
+            Ok(None) => {}
+            Err(reason) => report.skip(idx + 1, line.trim(), reason),
+        }
+    }
+    // the lowercase name of every Domain and Full rule
+    let names: Vec<Option<String>> = rules
+        .iter()
+        .map(|(_, _, rule)| match rule.match_type {
+            MatchType::Domain(_) | MatchType::Full(_) => Some(rule.pattern.to_ascii_lowercase()),
+            _ => None,
+        })
+        .collect();
+    // Important exceptions go first, so that a blocking rule they override
+    // no longer overrides anything itself.
+    let mut overridden = vec![false; rules.len()];
+    for exceptions in [true, false] {
+        let mut domains = HashSet::new();
+        let mut fulls = HashSet::new();
+        for (((_, _, rule), name), overridden) in
+            rules.iter().zip(names.iter()).zip(overridden.iter())
+        {
+            if let Some(name) = name {
+                if !*overridden && rule.important && rule.match_type.is_exception() == exceptions {
+                    match rule.match_type {
+                        MatchType::Domain(_) => domains.insert(name.as_str()),
+                        _ => fulls.insert(name.as_str()),
+                    };
+                }
+            }
+        }
+        if domains.is_empty() && fulls.is_empty() {
+            continue;
+        }
+        for (idx, ((_, _, rule), name)) in rules.iter().zip(names.iter()).enumerate() {
+            // an important exception beats every blocking rule, an important
+            // blocking rule only the exceptions without the option
+            if let Some(name) = name {
+                if rule.match_type.is_exception() != exceptions
+                    && (exceptions || !rule.important)
+                    && covered(name, rule.match_type, &domains, &fulls)
+                {
+                    overridden[idx] = true;
+                }
+            }
+        }
+    }
+    for ((line, text, rule), overridden) in rules.into_iter().zip(overridden) {
+        if overridden {
+            report.skip(line, text, SkipReason::Overridden);
+        } else {
+            matcher.reverse_insert(&rule.pattern, rule.match_type, value.clone());
+            report.inserted += 1;
+        }
+    }
+    report
+}
+
+// Whether every name a rule for `name` decides is also matched by one of the
+// Domain rules for `domains` or, for a Full rule, the Full rules for `fulls`.
+fn covered(
+    name: &str,
+    match_type: MatchType,
+    domains: &HashSet<&str>,
+    fulls: &HashSet<&str>,
+) -> bool {
+    if matches!(match_type, MatchType::Full(_)) && fulls.contains(name) {
+        return true;
+    }
+    let mut suffix = name;
+    loop {
+        if domains.contains(suffix) {
+            return true;
+        }
+        match suffix.find('.') {
+            Some(pos) => suffix = &suffix[pos + 1..],
+            None => return false,
+        }
+    }
+}
+
+/// Reads the filter list at `path` into `matcher`, like `insert_rules`.
+pub fn load_rules<V: Clone, P: AsRef<Path>>(
+    matcher: &mut impl DomainMatcher<V>,
+    path: P,
+    value: V,
+) -> io::Result<ImportReport> {
+    Ok(insert_rules(matcher, &fs::read_to_string(path)?, value))
+}
@@ -0,0 +1,165 @@
+//! Clash and Mihomo rule providers, in YAML (`payload:` followed by one
+//! `- entry` per line) or text (one entry per line) form.
+use super::{is_name, wildcard_regex, ImportReport, SkipReason};
+use crate::{DomainMatcher, MatchType};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// The `behavior` of a rule provider, which sets the syntax of its entries.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Behavior {
+    /// Bare names: `example.com` is a Full rule, `+.example.com` a Domain
+    /// rule, `.example.com` matches only the subdomains and a `*` label
+    /// exactly one label.
+    Domain,
+    /// `TYPE,value` entries, of which `DOMAIN`, `DOMAIN-SUFFIX`,
+    /// `DOMAIN-KEYWORD`, `DOMAIN-REGEX` and `DOMAIN-WILDCARD` name domains.
+    Classical,
+}
+
+/// Parses one payload entry into a pattern and its match type. Returns `None`
+/// for an entry no `MatchType` can express, such as `IP-CIDR,10.0.0.0/8` or
+/// `PROCESS-NAME,curl`.
+///
+/// Subdomain-only and wildcard entries become Regex rules.
+pub fn parse_entry(entry: &str, behavior: Behavior) -> Option<(String, MatchType)> {
+    match behavior {
+        Behavior::Domain => parse_domain(entry),
+        Behavior::Classical => {
+            let mut fields = entry.split(',').map(str::trim);
+            let rule_type = fields.next()?;
+            let value = fields.next().filter(|value| !value.is_empty())?;
+            // further fields, such as a policy, do not change the match
+            match rule_type.to_ascii_uppercase().as_str() {
+                "DOMAIN" => Some((value.to_string(), MatchType::Full(true))),
+                "DOMAIN-SUFFIX" => Some((value.to_string(), MatchType::Domain(true))),
+                "DOMAIN-KEYWORD" => Some((value.to_string(), MatchType::SubStr(true))),
+                "DOMAIN-REGEX" => Some((value.to_string(), MatchType::Regex(true))),
+                "DOMAIN-WILDCARD" => Some((glob_regex(value), MatchType::Regex(true))),
+                _ => None,
+            }
+        }
+    }
+}
+
+fn parse_domain(entry: &str) -> Option<(String, MatchType)> {
+    if let Some(name) = entry.strip_prefix("+.") {
+        return Some(name)
+            .filter(|name| is_name(name, false))
+            .map(|name| (name.to_string(), MatchType::Domain(true)));
+    }
+    let (name, subdomains_only) = match entry.strip_prefix('.') {
+        Some(name) => (name, true),
+        None => (entry, false),
+    };
+    if !is_name(name, true) {
+        return None;
+    }
+    if subdomains_only {
+        // `^example\.com$` becomes `\.example\.com$`
+        Some((
+            format!("\\.{}", &wildcard_regex(name)[1..]),
+            MatchType::Regex(true),
+        ))
+    } else if name.contains('*') {
+        Some((wildcard_regex(name), MatchType::Regex(true)))
+    } else {
+        Some((name.to_string(), MatchType::Full(true)))
+    }
+}
+
+// Why `parse_entry` rejected `entry`: a classical rule of another type is
+// unsupported, anything else malformed.
+fn skip_reason(entry: &str, behavior: Behavior) -> SkipReason {
+    let rule_type = entry.split(',').next().unwrap_or_default().trim();
+    match behavior {
+        Behavior::Classical if !rule_type.to_ascii_uppercase().starts_with("DOMAIN") => {
+            SkipReason::Unsupported
+        }
+        _ => SkipReason::Malformed,
+    }
+}
+
+// `DOMAIN-WILDCARD` globs: `*` stands for any run of characters, `?` for one.
+fn glob_regex(glob: &str) -> String {
+    let mut re = String::from("^");
+    for c in glob.chars() {
+        match c {
+            '*' => re.push_str(".*"),
+            '?' => re.push('.'),
+            c => re.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    re.push('$');
+    re
+}
+
+/// Returns the entries of a rule provider file with their line numbers, in
+/// either form. Comments, blank lines and the `payload:` key are left out.
+pub fn entries(text: &str) -> impl Iterator<Item = (usize, &str)> {
+    text.lines().enumerate().filter_map(|(idx, line)| {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line == "payload:" {
+            return None;
+        }
+        let entry = match line.strip_prefix('-') {
+            Some(item) => unquote(item.trim_start()),
+            None => line,
+        };
+        Some((idx + 1, entry))
+    })
+}
+
+// Strips the quotes and trailing comment of a YAML list item.
+fn unquote(item: &str) -> &str {
+    for quote in ['\'', '"'] {
+        if let Some(rest) = item.strip_prefix(quote) {
+            return match rest.find(quote) {
+                Some(end) => &rest[..end],
+                None => item,
+            };
+        }
+    }
+    match item.find(" #") {
+        Some(end) => item[..end].trim_end(),
+        None => item,
+    }
+}
+
+/// Inserts every entry of a rule provider into `matcher`, each with a clone
+/// of `value`, and reports the lines it could not express.
+pub fn insert_rules<V: Clone>(
+    matcher: &mut impl DomainMatcher<V>,
+    text: &str,
+    behavior: Behavior,
+    value: V,
+) -> ImportReport {
+    let mut report = ImportReport::default();
+    for (line, entry) in entries(text) {
+        match parse_entry(entry, behavior) {
+            Some((pattern, match_type)) => {
+                matcher.reverse_insert(&pattern, match_type, value.clone());
+                report.inserted += 1;
+            }
+            None => report.skip(line, entry, skip_reason(entry, behavior)),
+        }
+    }
+    report
+}
+
+/// Reads the rule provider file at `path` into `matcher`, like
+/// `insert_rules`.
+pub fn load_rules<V: Clone, P: AsRef<Path>>(
+    matcher: &mut impl DomainMatcher<V>,
+    path: P,
+    behavior: Behavior,
+    value: V,
+) -> io::Result<ImportReport> {
+    Ok(insert_rules(
+        matcher,
+        &fs::read_to_string(path)?,
+        behavior,
+        value,
+    ))
+}
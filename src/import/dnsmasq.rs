@@ -0,0 +1,84 @@
+//! dnsmasq `address=`, `server=` and `local=` lines for given domains.
+use super::{is_name, trim_dot, ImportReport, Rules, SkipReason, Target};
+use crate::{DomainMatcher, MatchType};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// Inserts a Domain rule for every domain of the `address=`, `server=` and
+/// `local=` lines of a dnsmasq configuration:
+///
+/// * `address=/example.com/0.0.0.0` answers with the address, `#` standing
+///   for `0.0.0.0` and `::`, and `address=/example.com/` with `Target::Block`;
+/// * `server=/example.com/1.1.1.1` forwards to the server;
+/// * `local=/example.com/` and `server=/example.com/` are `Target::Block`.
+///
+/// All `address=` lines of a domain make up one `Target::Address`, and all
+/// its `server=` lines one `Target::Upstream`. A line giving a domain another
+/// kind of target than an earlier one is skipped as `Overridden`. Other
+/// options, and lines for all domains such as `server=1.1.1.1`, are skipped as
+/// `Unsupported`.
+pub fn insert_rules(matcher: &mut impl DomainMatcher<Target>, text: &str) -> ImportReport {
+    let mut report = ImportReport::default();
+    let mut rules = Rules::default();
+    for (idx, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (key, value) = match line.split_once('=') {
+            Some((key, value)) => (key.trim(), value.trim()),
+            None => (line, ""),
+        };
+        let (names, target) = match (key, value.strip_prefix('/')) {
+            ("address" | "server" | "local", Some(value)) => match value.rsplit_once('/') {
+                Some((names, target)) => (names.split('/').map(trim_dot).collect(), target),
+                None => (Vec::new(), value),
+            },
+            _ => {
+                report.skip(idx + 1, line, SkipReason::Unsupported);
+                continue;
+            }
+        };
+        if names.contains(&"#") {
+            report.skip(idx + 1, line, SkipReason::Unsupported);
+            continue;
+        }
+        if names.is_empty() || !names.iter().all(|name| is_name(name, false)) {
+            report.skip(idx + 1, line, SkipReason::Malformed);
+            continue;
+        }
+        let target = match (key, target) {
+            ("local", _) | (_, "") => Target::Block,
+            ("address", "#") => Target::Address(vec![
+                IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+            ]),
+            ("address", addr) => match addr.parse() {
+                Ok(addr) => Target::Address(vec![addr]),
+                Err(_) => {
+                    report.skip(idx + 1, line, SkipReason::Malformed);
+                    continue;
+                }
+            },
+            (_, server) => Target::Upstream(vec![server.to_string()]),
+        };
+        for name in names {
+            if let Err(reason) = rules.add(name, MatchType::Domain(true), target.clone()) {
+                report.skip(idx + 1, line, reason);
+            }
+        }
+    }
+    report.inserted += rules.insert_into(matcher);
+    report
+}
+
+/// Reads the dnsmasq configuration at `path` into `matcher`, like
+/// `insert_rules`.
+pub fn load_rules<P: AsRef<Path>>(
+    matcher: &mut impl DomainMatcher<Target>,
+    path: P,
+) -> io::Result<ImportReport> {
+    Ok(insert_rules(matcher, &fs::read_to_string(path)?))
+}
@@ -0,0 +1,89 @@
+//! Hosts files (`0.0.0.0 ads.example.com`) and Pi-hole domain lists (one name
+//! per line).
+use super::{is_name, trim_dot, ImportReport, Rules, SkipReason, Target};
+use crate::{DomainMatcher, MatchType};
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+
+// Names every hosts file maps to the machine itself, which a blocklist
+// must not take over.
+const LOCAL_NAMES: [&str; 10] = [
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "ip6-localnet",
+    "ip6-mcastprefix",
+    "ip6-allnodes",
+    "ip6-allrouters",
+];
+
+/// Inserts every name of a hosts file as a Full rule whose value is its
+/// addresses, all of those given for the name on any line. A line holding
+/// only a name, as in a Pi-hole list, maps it to `0.0.0.0`. `#` starts a
+/// comment, and the names of the machine itself, such as `localhost`, are
+/// left out, as are lines naming an address such as `0.0.0.0 0.0.0.0`.
+pub fn insert_rules(matcher: &mut impl DomainMatcher<Target>, text: &str) -> ImportReport {
+    let mut report = ImportReport::default();
+    let mut rules = Rules::default();
+    for (idx, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        }
+        .trim();
+        let mut fields = line.split_whitespace().peekable();
+        let addr = match fields.peek() {
+            None => continue,
+            Some(field) => match field.parse::<IpAddr>() {
+                Ok(addr) => {
+                    fields.next();
+                    addr
+                }
+                // an IPv6 address with a zone, such as `fe80::1%lo0`
+                Err(_) if field.contains(':') => {
+                    report.skip(idx + 1, line, SkipReason::Unsupported);
+                    continue;
+                }
+                Err(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            },
+        };
+        let names: Vec<&str> = fields.map(trim_dot).collect();
+        if names.is_empty() || !names.iter().all(|name| is_name(name, false)) {
+            report.skip(idx + 1, line, SkipReason::Malformed);
+            continue;
+        }
+        // the `0.0.0.0 0.0.0.0` line at the top of many blocklists
+        if names.iter().any(|name| name.parse::<IpAddr>().is_ok()) {
+            report.skip(idx + 1, line, SkipReason::Unsupported);
+            continue;
+        }
+        for name in names {
+            if LOCAL_NAMES
+                .iter()
+                .any(|local| name.eq_ignore_ascii_case(local))
+            {
+                continue;
+            }
+            if let Err(reason) = rules.add(name, MatchType::Full(true), Target::Address(vec![addr]))
+            {
+                report.skip(idx + 1, line, reason);
+            }
+        }
+    }
+    report.inserted += rules.insert_into(matcher);
+    report
+}
+
+/// Reads the hosts file or Pi-hole list at `path` into `matcher`, like
+/// `insert_rules`.
+pub fn load_rules<P: AsRef<Path>>(
+    matcher: &mut impl DomainMatcher<Target>,
+    path: P,
+) -> io::Result<ImportReport> {
+    Ok(insert_rules(matcher, &fs::read_to_string(path)?))
+}
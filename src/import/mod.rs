@@ -0,0 +1,174 @@
+//! Loaders for the rule lists of other tools.
+//!
+//! Each loader inserts the rules it can express into any `DomainMatcher` and
+//! reports the lines it skipped. The caller still has to call `build()`.
+pub mod abp;
+pub mod clash;
+pub mod dnsmasq;
+pub mod hosts;
+pub mod unbound;
+#[cfg(feature = "pb")]
+pub mod v2fly;
+
+use crate::{DomainMatcher, MatchType};
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+/// What a resolver does with the names of a rule, kept as its value by the
+/// loaders of resolver configurations.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Target {
+    /// Answer with these addresses, e.g. the `0.0.0.0` and `::` sinkholes of
+    /// a hosts file.
+    Address(Vec<IpAddr>),
+    /// Answer that the name does not exist, e.g. dnsmasq `address=/x/`.
+    Block,
+    /// Forward the query to these servers, written as in the configuration,
+    /// such as `1.1.1.1#53`.
+    Upstream(Vec<String>),
+    /// Answer from an Unbound `local-zone` of this type, such as
+    /// `always_nxdomain` or `redirect`.
+    LocalZone(String),
+}
+
+/// Why a loader skipped a line.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum SkipReason {
+    /// A rule on something other than a domain name, such as `IP-CIDR`, or
+    /// with an option no `MatchType` can express.
+    Unsupported,
+    /// An element hiding or other cosmetic rule of an ad-block list.
+    Cosmetic,
+    /// A rule on the path or query of a URL, which a DNS lookup never sees.
+    UrlPath,
+    /// A rule that an `$important` rule of an ad-block list overrides, or
+    /// that gives a name of a resolver configuration another kind of target
+    /// than an earlier line.
+    Overridden,
+    /// A line that is not valid in its format.
+    Malformed,
+}
+
+/// A line a loader could not turn into a rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SkippedLine {
+    /// The line number, starting at 1.
+    pub line: usize,
+    pub text: String,
+    pub reason: SkipReason,
+}
+
+/// What a loader did with its input.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ImportReport {
+    /// How many rules were inserted.
+    pub inserted: usize,
+    pub skipped: Vec<SkippedLine>,
+}
+
+impl ImportReport {
+    /// How many lines were skipped for `reason`.
+    pub fn skipped_count(&self, reason: SkipReason) -> usize {
+        self.skipped
+            .iter()
+            .filter(|line| line.reason == reason)
+            .count()
+    }
+
+    fn skip(&mut self, line: usize, text: &str, reason: SkipReason) {
+        self.skipped.push(SkippedLine {
+            line,
+            text: text.to_string(),
+            reason,
+        });
+    }
+}
+
+// The rules of a resolver configuration by lowercase name, in the order the
+// names first appear. A matcher keeps the first value of a name, so repeated
+// names merge their targets here before anything is inserted.
+#[derive(Default)]
+struct Rules<'a> {
+    rules: Vec<(&'a str, MatchType, Target)>,
+    ids: HashMap<String, usize>,
+}
+
+impl<'a> Rules<'a> {
+    // Adds a rule, merging its addresses or servers into those of an earlier
+    // rule for the same name. A name already given another kind of target is
+    // `Overridden`.
+    fn add(
+        &mut self,
+        name: &'a str,
+        match_type: MatchType,
+        target: Target,
+    ) -> Result<(), SkipReason> {
+        let id = match self.ids.get(&name.to_ascii_lowercase()) {
+            Some(id) => *id,
+            None => {
+                self.ids.insert(name.to_ascii_lowercase(), self.rules.len());
+                self.rules.push((name, match_type, target));
+                return Ok(());
+            }
+        };
+        let (_, old_type, old) = &mut self.rules[id];
+        if *old_type != match_type {
+            return Err(SkipReason::Overridden);
+        }
+        match (old, target) {
+            (Target::Address(old), Target::Address(addrs)) => {
+                for addr in addrs {
+                    if !old.contains(&addr) {
+                        old.push(addr);
+                    }
+                }
+            }
+            (Target::Upstream(old), Target::Upstream(servers)) => old.extend(servers),
+            (old, target) if *old == target => {}
+            _ => return Err(SkipReason::Overridden),
+        }
+        Ok(())
+    }
+
+    // Inserts the rules into `matcher`, returning how many there were.
+    fn insert_into(self, matcher: &mut impl DomainMatcher<Target>) -> usize {
+        let count = self.rules.len();
+        for (name, match_type, target) in self.rules {
+            matcher.reverse_insert(name, match_type, target);
+        }
+        count
+    }
+}
+
+// Whether `name` is made of labels of letters, digits, `-` and `_`, or with
+// `wildcard` of `*` labels.
+fn is_name(name: &str, wildcard: bool) -> bool {
+    name.split('.').all(|label| {
+        (wildcard && label == "*")
+            || (!label.is_empty()
+                && label
+                    .chars()
+                    .all(|c| c.is_alphanumeric() || c == '-' || c == '_'))
+    })
+}
+
+// Strips the root dot of a fully qualified name, such as `example.com.`.
+fn trim_dot(name: &str) -> &str {
+    name.strip_suffix('.').unwrap_or(name)
+}
+
+// Rewrites a name with `*` labels, e.g. `www.*.example.com`, into a regex in
+// which each `*` stands for exactly one label.
+fn wildcard_regex(name: &str) -> String {
+    let labels: Vec<String> = name
+        .split('.')
+        .map(|label| {
+            if label == "*" {
+                "[^.]+".to_string()
+            } else {
+                regex::escape(label)
+            }
+        })
+        .collect();
+    format!("^{}$", labels.join("\\."))
+}
@@ -0,0 +1,162 @@
+//! Unbound `local-zone:`, `local-data:` and `forward-zone:` entries.
+use super::{is_name, trim_dot, ImportReport, Rules, SkipReason, Target};
+use crate::{DomainMatcher, MatchType};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+// A `forward-zone:` clause being read.
+struct ForwardZone<'a> {
+    line: usize,
+    text: &'a str,
+    name: Option<&'a str>,
+    servers: Vec<String>,
+}
+
+/// Inserts the zones and records of an Unbound configuration:
+///
+/// * `local-zone: "example.com." always_nxdomain` is a Domain rule with
+///   `Target::LocalZone` of its type;
+/// * `local-data: "ads.example.com. A 0.0.0.0"` is a Full rule with
+///   `Target::Address` of all A and AAAA records of the name;
+/// * a `forward-zone:` clause is a Domain rule for its `name:` with
+///   `Target::Upstream` of its `forward-addr:` and `forward-host:` servers.
+///
+/// A line giving a name another kind of target than an earlier one, such as
+/// `local-data:` for the name of a `local-zone:`, is skipped as `Overridden`.
+/// `server:` starts a clause and is ignored; any other option is skipped as
+/// `Unsupported`.
+pub fn insert_rules(matcher: &mut impl DomainMatcher<Target>, text: &str) -> ImportReport {
+    let mut report = ImportReport::default();
+    let mut rules = Rules::default();
+    let mut zone: Option<ForwardZone> = None;
+    for (idx, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None => line,
+        }
+        .trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (key, value) = match line.split_once(':') {
+            Some((key, value)) => (key.trim(), unquote(value.trim())),
+            None => {
+                report.skip(idx + 1, line, SkipReason::Malformed);
+                continue;
+            }
+        };
+        // a clause ends where the next one starts
+        if value.is_empty() {
+            if let Some(zone) = zone.take() {
+                add_zone(&mut rules, zone, &mut report);
+            }
+            match key {
+                "server" => {}
+                "forward-zone" => {
+                    zone = Some(ForwardZone {
+                        line: idx + 1,
+                        text: line,
+                        name: None,
+                        servers: Vec::new(),
+                    })
+                }
+                _ => report.skip(idx + 1, line, SkipReason::Unsupported),
+            }
+            continue;
+        }
+        if let Some(zone) = zone.as_mut() {
+            match key {
+                "name" => zone.name = Some(trim_dot(value)),
+                "forward-addr" | "forward-host" => zone.servers.push(value.to_string()),
+                _ => report.skip(idx + 1, line, SkipReason::Unsupported),
+            }
+            continue;
+        }
+        let added = match key {
+            "local-zone" => add_local_zone(&mut rules, value),
+            "local-data" => add_local_data(&mut rules, value),
+            _ => Err(SkipReason::Unsupported),
+        };
+        if let Err(reason) = added {
+            report.skip(idx + 1, line, reason);
+        }
+    }
+    if let Some(zone) = zone {
+        add_zone(&mut rules, zone, &mut report);
+    }
+    report.inserted += rules.insert_into(matcher);
+    report
+}
+
+fn unquote(value: &str) -> &str {
+    value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value)
+}
+
+// `"example.com." always_nxdomain`, in which the quotes are optional.
+fn add_local_zone<'a>(rules: &mut Rules<'a>, value: &'a str) -> Result<(), SkipReason> {
+    let mut fields = value.split_whitespace().map(unquote);
+    match (fields.next().map(trim_dot), fields.next(), fields.next()) {
+        (Some(name), Some(zone_type), None) if is_name(name, false) => rules.add(
+            name,
+            MatchType::Domain(true),
+            Target::LocalZone(zone_type.to_string()),
+        ),
+        _ => Err(SkipReason::Malformed),
+    }
+}
+
+// `ads.example.com. 3600 IN A 0.0.0.0`, with an optional TTL and class.
+fn add_local_data<'a>(rules: &mut Rules<'a>, value: &'a str) -> Result<(), SkipReason> {
+    let fields: Vec<&str> = value.split_whitespace().collect();
+    let name = match fields.first() {
+        Some(name) if is_name(trim_dot(name), false) => trim_dot(name),
+        _ => return Err(SkipReason::Malformed),
+    };
+    let rr_type = fields[1..]
+        .iter()
+        .position(|field| {
+            !field.bytes().all(|c| c.is_ascii_digit()) && !field.eq_ignore_ascii_case("IN")
+        })
+        .map(|pos| pos + 1)
+        .ok_or(SkipReason::Malformed)?;
+    match (
+        fields[rr_type].to_ascii_uppercase().as_str(),
+        &fields[rr_type + 1..],
+    ) {
+        ("A" | "AAAA", [addr]) => {
+            let addr = addr.parse().map_err(|_| SkipReason::Malformed)?;
+            rules.add(name, MatchType::Full(true), Target::Address(vec![addr]))
+        }
+        ("A" | "AAAA", _) => Err(SkipReason::Malformed),
+        _ => Err(SkipReason::Unsupported),
+    }
+}
+
+fn add_zone<'a>(rules: &mut Rules<'a>, zone: ForwardZone<'a>, report: &mut ImportReport) {
+    let added = match zone.name {
+        // the root zone holds every name
+        Some("" | ".") => Err(SkipReason::Unsupported),
+        Some(name) if is_name(name, false) && !zone.servers.is_empty() => rules.add(
+            name,
+            MatchType::Domain(true),
+            Target::Upstream(zone.servers),
+        ),
+        _ => Err(SkipReason::Malformed),
+    };
+    if let Err(reason) = added {
+        report.skip(zone.line, zone.text, reason);
+    }
+}
+
+/// Reads the Unbound configuration at `path` into `matcher`, like
+/// `insert_rules`.
+pub fn load_rules<P: AsRef<Path>>(
+    matcher: &mut impl DomainMatcher<Target>,
+    path: P,
+) -> io::Result<ImportReport> {
+    Ok(insert_rules(matcher, &fs::read_to_string(path)?))
+}
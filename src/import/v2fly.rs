@@ -0,0 +1,325 @@
+//! The source format of v2fly domain-list-community, which geosite.dat is
+//! compiled from.
+//!
+//! A directory holds one file per list, named after it. Each line is one of
+//!
+//! * `domain:google.com`, or just `google.com`, a Domain rule;
+//! * `full:www.google.com`, `keyword:google` or `regexp:^ad[0-9]+\.`;
+//! * `include:google`, all domains of another list, which attribute filters
+//!   such as `include:google @cn` or `include:google @-cn` narrow down;
+//!
+//! where rules may carry attributes such as `@cn` and `#` starts a comment.
+//!
+//! Unlike the other loaders of `import`, these lists are maintained by hand,
+//! so a line that does not parse is an error, as in the upstream compiler.
+use super::{is_name, SkipReason};
+use crate::geosite::{
+    self, AttributeFilter, Domain, Domain_Attribute, Domain_Type, LoadError, SiteGroup,
+    SiteGroupList,
+};
+use crate::mph::MphMatcher;
+use crate::DomainMatcher;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+
+#[derive(Debug)]
+pub enum SourceError {
+    Io(io::Error),
+    /// A line of a list that does not parse, with its line number.
+    Syntax {
+        list: String,
+        line: usize,
+        text: String,
+    },
+    /// An `include:` of a list that does not exist.
+    UnknownInclude {
+        list: String,
+        line: usize,
+        include: String,
+    },
+    /// Lists that include each other, from the first to the one including
+    /// it again.
+    IncludeCycle(Vec<String>),
+    /// Selecting or building a group failed.
+    Load(LoadError),
+}
+
+impl fmt::Display for SourceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SourceError::Io(e) => write!(f, "failed to read list: {}", e),
+            SourceError::Syntax { list, line, text } => {
+                write!(f, "{}:{}: invalid rule {}", list, line, text)
+            }
+            SourceError::UnknownInclude {
+                list,
+                line,
+                include,
+            } => write!(f, "{}:{}: no list named {}", list, line, include),
+            SourceError::IncludeCycle(lists) => {
+                write!(f, "lists include each other: {}", lists.join(" -> "))
+            }
+            SourceError::Load(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for SourceError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SourceError::Io(e) => Some(e),
+            SourceError::Load(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SourceError {
+    fn from(e: io::Error) -> Self {
+        SourceError::Io(e)
+    }
+}
+
+impl From<LoadError> for SourceError {
+    fn from(e: LoadError) -> Self {
+        SourceError::Load(e)
+    }
+}
+
+/// A line of a list.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Entry {
+    Domain(Domain),
+    Include {
+        list: String,
+        filters: Vec<AttributeFilter>,
+    },
+}
+
+/// Parses one line of a list. Returns `Ok(None)` for a blank line or a
+/// comment.
+pub fn parse_line(line: &str) -> Result<Option<Entry>, SkipReason> {
+    let line = match line.find('#') {
+        Some(pos) => &line[..pos],
+        None => line,
+    }
+    .trim();
+    if line.is_empty() {
+        return Ok(None);
+    }
+    let mut fields = line.split_whitespace();
+    let rule = fields.next().ok_or(SkipReason::Malformed)?;
+    let attrs: Vec<&str> = fields
+        .map(|field| field.strip_prefix('@').filter(|attr| !attr.is_empty()))
+        .collect::<Option<_>>()
+        .ok_or(SkipReason::Malformed)?;
+    let (kind, value) = match rule.split_once(':') {
+        Some((kind, value)) => (kind, value),
+        None => ("domain", rule),
+    };
+    if kind == "include" {
+        // list names are file names, such as `geolocation-!cn`
+        if value.is_empty() || value.contains(['/', '\\']) {
+            return Err(SkipReason::Malformed);
+        }
+        let filters = attrs
+            .iter()
+            .map(|attr| {
+                let (negated, key) = match attr.strip_prefix('-').or(attr.strip_prefix('!')) {
+                    Some(key) => (true, key),
+                    None => (false, *attr),
+                };
+                AttributeFilter {
+                    key: key.to_string(),
+                    value: None,
+                    negated,
+                }
+            })
+            .collect();
+        return Ok(Some(Entry::Include {
+            list: value.to_ascii_lowercase(),
+            filters,
+        }));
+    }
+    let field_type = match kind {
+        "domain" => Domain_Type::Domain,
+        "full" => Domain_Type::Full,
+        "keyword" => Domain_Type::Plain,
+        "regexp" => Domain_Type::Regex,
+        _ => return Err(SkipReason::Malformed),
+    };
+    let valid = match field_type {
+        Domain_Type::Regex => regex::Regex::new(value).is_ok(),
+        Domain_Type::Plain => !value.is_empty(),
+        Domain_Type::Domain | Domain_Type::Full => is_name(value, false),
+    };
+    if !valid || attrs.iter().any(|attr| attr.starts_with(['-', '!'])) {
+        return Err(SkipReason::Malformed);
+    }
+    let mut domain = Domain::new();
+    domain.set_field_type(field_type);
+    domain.set_value(value.to_string());
+    for attr in attrs {
+        let mut attribute = Domain_Attribute::new();
+        attribute.set_key(attr.to_string());
+        attribute.set_bool_value(true);
+        domain.mut_attribute().push(attribute);
+    }
+    Ok(Some(Entry::Domain(domain)))
+}
+
+/// The text of a set of lists, by name.
+#[derive(Clone, Debug, Default)]
+pub struct Sources {
+    lists: BTreeMap<String, String>,
+}
+
+impl Sources {
+    pub fn new() -> Sources {
+        Sources::default()
+    }
+
+    /// Reads every file of `dir` as the list named after the file. Hidden
+    /// files and subdirectories are left out.
+    pub fn read_dir<P: AsRef<Path>>(dir: P) -> Result<Sources, SourceError> {
+        let mut sources = Sources::new();
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') || !entry.file_type()?.is_file() {
+                continue;
+            }
+            sources.insert(&name, fs::read_to_string(entry.path())?);
+        }
+        Ok(sources)
+    }
+
+    /// Adds the list `name`, replacing one of the same name. Names ignore
+    /// ASCII case.
+    pub fn insert(&mut self, name: &str, text: String) {
+        self.lists.insert(name.to_ascii_lowercase(), text);
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.lists.keys().map(|name| name.as_str())
+    }
+
+    /// Compiles every list into a group tagged with its upper case name, as
+    /// the upstream compiler does.
+    pub fn compile(&self) -> Result<SiteGroupList, SourceError> {
+        let mut compiler = Compiler::new(self);
+        let mut site_group_list = SiteGroupList::new();
+        for name in self.lists.keys() {
+            site_group_list
+                .mut_site_group()
+                .push(group(name, compiler.resolve(name)?.to_vec()));
+        }
+        Ok(site_group_list)
+    }
+
+    /// Compiles the list `name` and the lists it includes into one group.
+    pub fn compile_list(&self, name: &str) -> Result<SiteGroup, SourceError> {
+        let name = name.to_ascii_lowercase();
+        if !self.lists.contains_key(&name) {
+            return Err(LoadError::UnknownTag(name).into());
+        }
+        let domains = Compiler::new(self).resolve(&name)?.to_vec();
+        Ok(group(&name, domains))
+    }
+}
+
+fn group(name: &str, domains: Vec<Domain>) -> SiteGroup {
+    let mut group = SiteGroup::new();
+    group.set_tag(name.to_ascii_uppercase());
+    group.set_domain(domains.into());
+    group
+}
+
+// Resolves includes depth first, compiling each list once.
+struct Compiler<'a> {
+    sources: &'a Sources,
+    done: HashMap<String, Vec<Domain>>,
+    // the lists being resolved, outermost first
+    stack: Vec<String>,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(sources: &'a Sources) -> Compiler<'a> {
+        Compiler {
+            sources,
+            done: HashMap::new(),
+            stack: Vec::new(),
+        }
+    }
+
+    fn resolve(&mut self, name: &str) -> Result<&[Domain], SourceError> {
+        if !self.done.contains_key(name) {
+            if let Some(pos) = self.stack.iter().position(|list| list == name) {
+                let mut cycle = self.stack[pos..].to_vec();
+                cycle.push(name.to_string());
+                return Err(SourceError::IncludeCycle(cycle));
+            }
+            self.stack.push(name.to_string());
+            let domains = self.compile(name)?;
+            self.stack.pop();
+            self.done.insert(name.to_string(), domains);
+        }
+        Ok(&self.done[name])
+    }
+
+    fn compile(&mut self, name: &str) -> Result<Vec<Domain>, SourceError> {
+        let sources = self.sources;
+        let mut domains = Vec::new();
+        for (idx, line) in sources.lists[name].lines().enumerate() {
+            let entry = parse_line(line).map_err(|_| SourceError::Syntax {
+                list: name.to_string(),
+                line: idx + 1,
+                text: line.trim().to_string(),
+            })?;
+            match entry {
+                None => {}
+                Some(Entry::Domain(domain)) => domains.push(domain),
+                Some(Entry::Include { list, filters }) => {
+                    if !sources.lists.contains_key(&list) {
+                        return Err(SourceError::UnknownInclude {
+                            list: name.to_string(),
+                            line: idx + 1,
+                            include: list,
+                        });
+                    }
+                    domains.extend(
+                        self.resolve(&list)?
+                            .iter()
+                            .filter(|domain| filters.iter().all(|filter| filter.matches(domain)))
+                            .cloned(),
+                    );
+                }
+            }
+        }
+        Ok(domains)
+    }
+}
+
+/// Writes the domains of `group` in the source format, one per line.
+/// Includes are written out as the domains they stand for.
+pub fn write_list<W: Write>(writer: &mut W, group: &SiteGroup) -> io::Result<()> {
+    for domain in group.domain.iter() {
+        writeln!(writer, "{}", domain)?;
+    }
+    Ok(())
+}
+
+/// Loads the group selected by `selector`, e.g. `google@cn`, from the lists
+/// in `dir` into a built `MphMatcher`, like `geosite::load_group` does from
+/// a geosite.dat file.
+pub fn load_group<P: AsRef<Path>>(dir: P, selector: &str) -> Result<MphMatcher, SourceError> {
+    let (tag, filters) = geosite::parse_selector(selector)?;
+    let group = Sources::read_dir(dir)?.compile_list(tag)?;
+    let mut matcher = MphMatcher::new(1);
+    geosite::insert_filtered_group(&mut matcher, &group, &filters);
+    matcher.build().map_err(LoadError::from)?;
+    Ok(matcher)
+}
@@ -0,0 +1,288 @@
+//! Binary format of a prebuilt `MphMatcher`.
+//!
+//! A file is a 16 byte header followed by the body. The header holds the magic
+//! `DMPH`, the format version, the body length and the `mem_hash` of the body,
+//! all as little endian `u32`. The body is a sequence of sections made of
+//! little endian `u32` values, so every array starts 4-byte aligned:
+//!
+//! * flags, where bit 0 marks a case sensitive matcher
+//! * `level0_mask`, `level1_mask`, then `level0` and `level1`
+//! * the sorted rule keys and the rule id of each key, `u32::MAX` for a key
+//!   whose rules were all removed
+//! * the rule patterns and their match types, with bit 3 set for removed
+//!   rules
+//! * the trie, fail links and node rules of the `ACAutomaton`, with the
+//!   Full or Domain rule and the SubStr rule of each node in arrays of their own
+//! * the regex patterns and their rule ids
+//!
+//! An array is stored as its length followed by its items. A string table is
+//! stored as its length, `len + 1` byte offsets and the concatenated bytes
+//! padded to a multiple of 4.
+use crate::mem_hash::MemHash;
+use crate::MatchType;
+use std::fmt;
+use std::io;
+
+pub(crate) const MAGIC: &[u8; 4] = b"DMPH";
+pub(crate) const VERSION: u32 = 1;
+pub(crate) const HEADER_SIZE: usize = 16;
+
+#[derive(Debug)]
+pub enum FormatError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    ChecksumMismatch,
+    Truncated,
+    Corrupted,
+    Unsupported(&'static str),
+}
+
+impl fmt::Display for FormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FormatError::Io(e) => write!(f, "failed to read index: {}", e),
+            FormatError::BadMagic => write!(f, "not a matcher index"),
+            FormatError::UnsupportedVersion(v) => write!(f, "unsupported index version {}", v),
+            FormatError::ChecksumMismatch => write!(f, "index checksum mismatch"),
+            FormatError::Truncated => write!(f, "index is truncated"),
+            FormatError::Corrupted => write!(f, "index is corrupted"),
+            FormatError::Unsupported(what) => write!(f, "index holds unsupported {}", what),
+        }
+    }
+}
+
+impl std::error::Error for FormatError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FormatError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for FormatError {
+    fn from(e: io::Error) -> Self {
+        FormatError::Io(e)
+    }
+}
+
+pub(crate) fn encode_match_type(match_type: MatchType) -> u32 {
+    let (kind, v) = match match_type {
+        MatchType::Domain(v) => (0, v),
+        MatchType::SubStr(v) => (1, v),
+        MatchType::Full(v) => (2, v),
+        MatchType::Regex(v) => (3, v),
+    };
+    kind << 1 | v as u32
+}
+
+/// Set in the encoded match type of a removed rule.
+pub(crate) const REMOVED: u32 = 1 << 3;
+
+pub(crate) fn decode_match_type(v: u32) -> Result<MatchType, FormatError> {
+    let v = v & !REMOVED;
+    let b = v & 1 == 1;
+    match v >> 1 {
+        0 => Ok(MatchType::Domain(b)),
+        1 => Ok(MatchType::SubStr(b)),
+        2 => Ok(MatchType::Full(b)),
+        3 => Ok(MatchType::Regex(b)),
+        _ => Err(FormatError::Corrupted),
+    }
+}
+
+/// Prepends the header to `body`.
+pub(crate) fn seal(body: Vec<u8>) -> Vec<u8> {
+    let mut out = Vec::with_capacity(HEADER_SIZE + body.len());
+    out.extend_from_slice(MAGIC);
+    out.extend_from_slice(&VERSION.to_le_bytes());
+    out.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    out.extend_from_slice(&0u32.mem_hash(&body).to_le_bytes());
+    out.extend_from_slice(&body);
+    out
+}
+
+/// Checks the header of `bytes` and returns the body.
+pub(crate) fn open(bytes: &[u8]) -> Result<&[u8], FormatError> {
+    if bytes.len() < HEADER_SIZE {
+        return Err(FormatError::Truncated);
+    }
+    if &bytes[..4] != MAGIC {
+        return Err(FormatError::BadMagic);
+    }
+    let header = U32Array {
+        bytes: &bytes[4..HEADER_SIZE],
+    };
+    if header.get(0) != VERSION {
+        return Err(FormatError::UnsupportedVersion(header.get(0)));
+    }
+    let body = &bytes[HEADER_SIZE..];
+    if body.len() != header.get(1) as usize {
+        return Err(FormatError::Truncated);
+    }
+    if 0u32.mem_hash(body) != header.get(2) {
+        return Err(FormatError::ChecksumMismatch);
+    }
+    Ok(body)
+}
+
+#[derive(Default)]
+pub(crate) struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub(crate) fn new() -> Writer {
+        Writer { buf: Vec::new() }
+    }
+
+    pub(crate) fn u32(&mut self, v: u32) {
+        self.buf.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub(crate) fn u32s<I: ExactSizeIterator<Item = u32>>(&mut self, items: I) {
+        self.u32(items.len() as u32);
+        for v in items {
+            self.u32(v);
+        }
+    }
+
+    pub(crate) fn strs<'a, I: ExactSizeIterator<Item = &'a str> + Clone>(&mut self, items: I) {
+        self.u32(items.len() as u32);
+        let mut offset = 0;
+        self.u32(offset);
+        for s in items.clone() {
+            offset += s.len() as u32;
+            self.u32(offset);
+        }
+        for s in items {
+            self.buf.extend_from_slice(s.as_bytes());
+        }
+        self.buf.resize((self.buf.len() + 3) & !3, 0);
+    }
+
+    pub(crate) fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// A little endian `u32` array read in place.
+#[derive(Copy, Clone)]
+pub(crate) struct U32Array<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> U32Array<'a> {
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.bytes.len() / 4
+    }
+
+    #[inline(always)]
+    pub(crate) fn get(&self, idx: usize) -> u32 {
+        let b = &self.bytes[idx * 4..idx * 4 + 4];
+        u32::from_le_bytes([b[0], b[1], b[2], b[3]])
+    }
+
+    pub(crate) fn iter(&self) -> impl ExactSizeIterator<Item = u32> + 'a {
+        let bytes = self.bytes;
+        (0..self.len()).map(move |idx| U32Array { bytes }.get(idx))
+    }
+}
+
+/// A string table read in place.
+#[derive(Copy, Clone)]
+pub(crate) struct StrTable<'a> {
+    offsets: U32Array<'a>,
+    bytes: &'a [u8],
+}
+
+impl<'a> StrTable<'a> {
+    #[inline(always)]
+    pub(crate) fn len(&self) -> usize {
+        self.offsets.len() - 1
+    }
+
+    /// Returns the raw bytes of the `idx`-th string.
+    #[inline(always)]
+    pub(crate) fn get_bytes(&self, idx: usize) -> &'a [u8] {
+        &self.bytes[self.offsets.get(idx) as usize..self.offsets.get(idx + 1) as usize]
+    }
+
+    /// Returns the `idx`-th string.
+    #[inline(always)]
+    pub(crate) fn get(&self, idx: usize) -> &'a str {
+        // `Reader::strs` checked that the table is UTF-8 and that every offset
+        // is on a char boundary.
+        unsafe { std::str::from_utf8_unchecked(self.get_bytes(idx)) }
+    }
+
+    pub(crate) fn iter(&self) -> impl ExactSizeIterator<Item = &'a str> + 'a {
+        let table = *self;
+        (0..self.len()).map(move |idx| table.get(idx))
+    }
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], FormatError> {
+        if self.bytes.len() < len {
+            return Err(FormatError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(len);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<u32, FormatError> {
+        Ok(U32Array {
+            bytes: self.take(4)?,
+        }
+        .get(0))
+    }
+
+    pub(crate) fn u32s(&mut self) -> Result<U32Array<'a>, FormatError> {
+        let len = self.u32()? as usize;
+        let bytes = self.take(len.checked_mul(4).ok_or(FormatError::Corrupted)?)?;
+        Ok(U32Array { bytes })
+    }
+
+    pub(crate) fn strs(&mut self) -> Result<StrTable<'a>, FormatError> {
+        let len = self.u32()? as usize;
+        let offsets = U32Array {
+            bytes: self.take(len.checked_add(1).ok_or(FormatError::Corrupted)? * 4)?,
+        };
+        let total = offsets.get(len) as usize;
+        let bytes = self.take(total)?;
+        self.take((4 - total % 4) % 4)?;
+        let text = std::str::from_utf8(bytes).map_err(|_| FormatError::Corrupted)?;
+        for idx in 0..len {
+            let (start, end) = (offsets.get(idx) as usize, offsets.get(idx + 1) as usize);
+            if start > end || !text.is_char_boundary(start) || !text.is_char_boundary(end) {
+                return Err(FormatError::Corrupted);
+            }
+        }
+        Ok(StrTable { offsets, bytes })
+    }
+
+    /// Returns the bytes not read yet.
+    pub(crate) fn remaining(&self) -> &'a [u8] {
+        self.bytes
+    }
+
+    pub(crate) fn finish(&self) -> Result<(), FormatError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(FormatError::Corrupted)
+        }
+    }
+}
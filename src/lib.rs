@@ -1,16 +1,46 @@
 pub mod ac_automaton;
+#[cfg(feature = "ffi")]
+pub mod ffi;
 #[cfg(feature = "pb")]
 pub mod geosite;
+pub mod group;
+#[cfg(feature = "idna")]
+pub mod idn;
+pub mod import;
+pub mod index;
 mod mem_hash;
 pub mod mph;
+mod regex_matcher;
+#[cfg(feature = "arc-swap")]
+pub mod shared;
 #[cfg(all(test, feature = "pb"))]
+#[allow(clippy::bool_assert_comparison)]
 mod test;
 
-#[derive(Copy, Clone)]
+use deepsize::DeepSizeOf;
+use std::fmt;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum MatchType {
     Domain(bool),
     SubStr(bool),
     Full(bool),
+    Regex(bool),
+}
+
+deepsize::known_deep_size!(0; MatchType);
+
+impl MatchType {
+    /// Whether the rule was inserted with `false`, i.e. carves matching
+    /// domains out of the other rules.
+    pub fn is_exception(self) -> bool {
+        match self {
+            MatchType::Domain(v)
+            | MatchType::SubStr(v)
+            | MatchType::Full(v)
+            | MatchType::Regex(v) => !v,
+        }
+    }
 }
 
 impl From<bool> for MatchType {
@@ -18,9 +48,265 @@ impl From<bool> for MatchType {
         MatchType::Full(v)
     }
 }
-pub trait DomainMatcher {
-    fn reverse_insert(&mut self, input_string: &str, match_type: MatchType);
+
+// rule id stored for a hash key or trie node without a rule
+pub(crate) const NO_RULE: u32 = u32::MAX;
+
+/// The longest domain name, without the root dot, allowed by RFC 1035.
+pub const MAX_NAME_LEN: usize = 253;
+/// The longest label allowed by RFC 1035.
+pub const MAX_LABEL_LEN: usize = 63;
+
+/// Returns the form of `query_string` every matcher looks up: one trailing
+/// root dot is stripped, so `example.com.` is queried as `example.com`.
+///
+/// Returns `None` for an empty name, a name with an empty label such as
+/// `.example.com` or `example..com`, or one longer than `MAX_NAME_LEN` or with
+/// a label longer than `MAX_LABEL_LEN`. Such a query matches no rule.
+pub fn normalize_query(query_string: &str) -> Option<&str> {
+    let name = query_string.strip_suffix('.').unwrap_or(query_string);
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return None;
+    }
+    if name
+        .split('.')
+        .any(|label| label.is_empty() || label.len() > MAX_LABEL_LEN)
+    {
+        return None;
+    }
+    Some(name)
+}
+
+/// The rule that made a query hit.
+///
+/// `index` is the position of the rule in insertion order since the last
+/// `clear()`, so it stays stable across `build()`.
+#[derive(Debug, PartialEq, Eq)]
+pub struct MatchedRule<'a, V = ()> {
+    pub pattern: &'a str,
+    pub match_type: MatchType,
+    pub index: usize,
+    pub value: &'a V,
+}
+
+impl<'a, V> Clone for MatchedRule<'a, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, V> Copy for MatchedRule<'a, V> {}
+
+/// The rules matching a query, from the most to the least specific. Returned
+/// by `DomainMatcher::reverse_query_all`.
+pub struct Matches<'a, V = ()> {
+    patterns: &'a [Rule<V>],
+    rule_ids: std::vec::IntoIter<u32>,
+}
+
+impl<'a, V> Matches<'a, V> {
+    pub(crate) fn new(patterns: &'a [Rule<V>], rule_ids: Vec<u32>) -> Matches<'a, V> {
+        Matches {
+            patterns,
+            rule_ids: rule_ids.into_iter(),
+        }
+    }
+}
+
+impl<'a, V> Iterator for Matches<'a, V> {
+    type Item = MatchedRule<'a, V>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let patterns = self.patterns;
+        self.rule_ids
+            .next()
+            .map(|id| patterns[id as usize].matched(id as usize))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.rule_ids.size_hint()
+    }
+}
+
+impl<'a, V> ExactSizeIterator for Matches<'a, V> {}
+
+#[derive(DeepSizeOf)]
+pub(crate) struct Rule<V> {
+    pattern: String,
+    match_type: MatchType,
+    value: V,
+    // Removed rules keep their slot so that rule ids stay stable.
+    removed: bool,
+}
+
+impl<V> Rule<V> {
+    pub(crate) fn new(pattern: &str, match_type: MatchType, value: V) -> Rule<V> {
+        Rule {
+            pattern: pattern.to_string(),
+            match_type,
+            value,
+            removed: false,
+        }
+    }
+
+    // Whether this live rule was inserted as `pattern` with `match_type`.
+    fn is(&self, pattern: &str, match_type: MatchType, case_sensitive: bool) -> bool {
+        !self.removed
+            && self.match_type == match_type
+            && if case_sensitive {
+                self.pattern == pattern
+            } else {
+                self.pattern.eq_ignore_ascii_case(pattern)
+            }
+    }
+
+    // Whether this live Full or Domain rule owns the hash key `key`, which is
+    // stored lowercase unless the matcher is case sensitive.
+    pub(crate) fn has_key(&self, key: &str, case_sensitive: bool) -> bool {
+        let eq = |pattern: &str, key: &str| {
+            if case_sensitive {
+                pattern == key
+            } else {
+                pattern.eq_ignore_ascii_case(key)
+            }
+        };
+        match self.match_type {
+            _ if self.removed => false,
+            MatchType::Full(_) => eq(&self.pattern, key),
+            MatchType::Domain(_) => {
+                eq(&self.pattern, key)
+                    || key
+                        .strip_prefix('.')
+                        .is_some_and(|key| eq(&self.pattern, key))
+            }
+            _ => false,
+        }
+    }
+
+    #[inline(always)]
+    pub(crate) fn matched(&self, index: usize) -> MatchedRule<'_, V> {
+        MatchedRule {
+            pattern: &self.pattern,
+            match_type: self.match_type,
+            index,
+            value: &self.value,
+        }
+    }
+}
+
+/// Returns the live rules of `patterns` with their ids.
+pub(crate) fn live_rules<V>(patterns: &[Rule<V>]) -> impl Iterator<Item = (u32, &Rule<V>)> {
+    patterns
+        .iter()
+        .enumerate()
+        .filter(|(_, rule)| !rule.removed)
+        .map(|(id, rule)| (id as u32, rule))
+}
+
+/// Whether rule `new` takes a hash key over from rule `old`, which owns it. A
+/// key keeps the rule it was first inserted for, except that a Full rule takes
+/// the bare name over from a Domain rule.
+pub(crate) fn takes_key<V>(patterns: &[Rule<V>], old: u32, new: u32) -> bool {
+    matches!(patterns[old as usize].match_type, MatchType::Domain(_))
+        && matches!(patterns[new as usize].match_type, MatchType::Full(_))
+}
+
+/// Returns the live rule owning the hash key `key`: the first Full rule for
+/// it, or else the first Domain rule.
+pub(crate) fn key_owner<V>(patterns: &[Rule<V>], key: &str, case_sensitive: bool) -> Option<u32> {
+    live_rules(patterns)
+        .filter(|(_, rule)| rule.has_key(key, case_sensitive))
+        .min_by_key(|(_, rule)| !matches!(rule.match_type, MatchType::Full(_)))
+        .map(|(id, _)| id)
+}
+
+/// Marks every live rule inserted as `pattern` with `match_type` as removed
+/// and returns their ids. Patterns are compared like queries.
+pub(crate) fn remove_rules<V>(
+    patterns: &mut [Rule<V>],
+    pattern: &str,
+    match_type: MatchType,
+    case_sensitive: bool,
+) -> Vec<u32> {
+    let mut removed = Vec::new();
+    for (id, rule) in patterns.iter_mut().enumerate() {
+        if rule.is(pattern, match_type, case_sensitive) {
+            rule.removed = true;
+            removed.push(id as u32);
+        }
+    }
+    removed
+}
+
+/// Why `DomainMatcher::build` failed. Queries on a matcher whose build failed
+/// may miss rules but never report a rule that does not match.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuildError {
+    /// No seed below the limit placed every rule, even in the largest tables
+    /// tried.
+    SeedLimitExceeded(u32),
+    /// More rules than a table index can address.
+    TooManyRules(usize),
+    /// A Regex pattern that does not compile, or does not fit in the set with
+    /// the patterns inserted before it.
+    InvalidRegex(String),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::SeedLimitExceeded(limit) => {
+                write!(f, "no hash seed below {} fits every rule", limit)
+            }
+            BuildError::TooManyRules(n) => write!(f, "too many rules: {}", n),
+            BuildError::InvalidRegex(pattern) => write!(f, "invalid regex: {}", pattern),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// A set of domain rules, each carrying a value of type `V`.
+///
+/// Use `V = ()` for a plain set. When the same pattern is inserted twice with
+/// the same match type, the first value is kept.
+///
+/// A rule inserted with `false` is an exception: a query it decides does not
+/// match. When several rules match, the most specific one decides:
+///
+/// 1. the Full rule, or else the Domain rule with the longest suffix, so that
+///    an exception for `cdn.example.com` overrides a rule for `example.com`;
+/// 2. otherwise a SubStr rule, then a Regex rule. Within each of these stages
+///    exceptions override the other rules, and ties go to the earliest
+///    inserted rule.
+///
+/// Queries go through `normalize_query` first; one it rejects matches
+/// nothing.
+///
+/// Rules may be inserted and removed after `build()`. Such a change applies
+/// at once, without rebuilding the matcher.
+///
+/// Queries take `&self` and share no hidden state, so the matchers of this
+/// crate are `Send` and `Sync` when `V` is and can serve many threads at
+/// once; see `shared::SharedMatcher` to replace one while it does.
+pub trait DomainMatcher<V = ()> {
+    fn reverse_insert(&mut self, input_string: &str, match_type: MatchType, value: V);
+    /// Removes every rule inserted as `pattern` with `match_type`, comparing
+    /// patterns like queries, and returns whether there was one. The ids of
+    /// the other rules do not change.
+    fn remove(&mut self, pattern: &str, match_type: MatchType) -> bool;
     fn reverse_query(&self, query_string: &str) -> bool;
-    fn build(&mut self);
+    /// Like `reverse_query`, but reports which rule matched.
+    fn reverse_query_rule(&self, query_string: &str) -> Option<MatchedRule<'_, V>>;
+    /// Returns every rule matching `query_string`, exceptions included, from
+    /// the most to the least specific. The first one decides the query.
+    fn reverse_query_all(&self, query_string: &str) -> Matches<'_, V>;
+    /// Returns the value of the rule that matched.
+    fn reverse_query_value(&self, query_string: &str) -> Option<&V> {
+        self.reverse_query_rule(query_string).map(|rule| rule.value)
+    }
+    /// Prepares the inserted rules for queries. Only `MphMatcher` can fail,
+    /// when its seed search hits the limit set by `set_seed_limit`.
+    fn build(&mut self) -> Result<(), BuildError>;
     fn clear(&mut self);
 }
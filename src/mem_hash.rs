@@ -2,6 +2,9 @@ use std::num::Wrapping;
 
 pub trait MemHash {
     fn mem_hash<T: AsRef<[u8]>>(self, bytes: T) -> u32;
+    /// Same as `mem_hash` of the ASCII lowercase copy of `bytes`, without
+    /// making the copy.
+    fn mem_hash_ignore_ascii_case<T: AsRef<[u8]>>(self, bytes: T) -> u32;
 }
 
 // Constants for multiplication: four random odd 64-bit numbers.
@@ -12,78 +15,126 @@ const M4: Wrapping<u64> = Wrapping(15839092249703872147);
 
 #[inline(always)]
 fn rotl31(x: Wrapping<u64>) -> Wrapping<u64> {
-    return (x << 31) | (x >> (64 - 31));
+    (x << 31) | (x >> (64 - 31))
+}
+
+const LO: u64 = 0x0101010101010101;
+const HI: u64 = 0x8080808080808080;
+
+/// Lowercases the ASCII letters among the bytes of `x`.
+#[inline(always)]
+fn fold_u64(x: u64) -> u64 {
+    let low7 = x & !HI;
+    // the high bit of a byte is set if its low 7 bits are >= 'A', resp. > 'Z'
+    let ge_a = low7 + LO * (0x80 - b'A' as u64);
+    let gt_z = low7 + LO * (0x80 - b'Z' as u64 - 1);
+    x | ((ge_a & !gt_z & !x & HI) >> 2)
+}
+
+#[inline(always)]
+unsafe fn load_u64(ptr: *const u8, fold: bool) -> u64 {
+    let v = (ptr as *const u64).read_unaligned();
+    if fold {
+        fold_u64(v)
+    } else {
+        v
+    }
+}
+
+#[inline(always)]
+unsafe fn load_u32(ptr: *const u8, fold: bool) -> u64 {
+    let v = (ptr as *const u32).read_unaligned() as u64;
+    if fold {
+        fold_u64(v)
+    } else {
+        v
+    }
+}
+
+#[inline(always)]
+fn load_u8(b: u8, fold: bool) -> u64 {
+    if fold {
+        b.to_ascii_lowercase() as u64
+    } else {
+        b as u64
+    }
 }
 
 impl MemHash for u32 {
     fn mem_hash<T: AsRef<[u8]>>(self, bytes: T) -> u32 {
-        let p = bytes.as_ref();
-        let mut ptr = bytes.as_ref().as_ptr();
-        let mut len = bytes.as_ref().len();
-        let mut h = Wrapping((self as usize + len) as u64);
+        hash(self, bytes.as_ref(), false)
+    }
 
-        loop {
-            match len {
-                0 => break,
-                1..=3 => {
-                    h ^= Wrapping(p[0] as u64);
-                    h ^= Wrapping(p[len >> 1] as u64) << 8;
-                    h ^= Wrapping(p[len - 1] as u64) << 16;
-                    h = rotl31(h * M1) * M2;
-                    break;
-                }
-                4..=8 => unsafe {
-                    h ^= Wrapping((ptr as *const u32).read_unaligned() as u64);
-                    h ^= Wrapping(
-                        (ptr.offset((len - 4) as isize) as *const u32).read_unaligned() as u64,
-                    ) << 32;
-                    h = rotl31(h * M1) * M2;
-                    break;
-                },
-                9..=16 => unsafe {
-                    h ^= Wrapping((ptr as *const u64).read_unaligned());
-                    h = rotl31(h * M1) * M2;
-                    h ^= Wrapping((ptr.offset((len - 8) as isize) as *const u64).read_unaligned());
-                    h = rotl31(h * M1) * M2;
-                    break;
-                },
-                17..=32 => unsafe {
-                    h ^= Wrapping((ptr as *const u64).read_unaligned());
-                    h = rotl31(h * M1) * M2;
-                    h ^= Wrapping((ptr.offset(8) as *const u64).read_unaligned());
-                    h = rotl31(h * M1) * M2;
-                    h ^= Wrapping((ptr.offset((len - 16) as isize) as *const u64).read_unaligned());
-                    h = rotl31(h * M1) * M2;
-                    h ^= Wrapping((ptr.offset((len - 8) as isize) as *const u64).read_unaligned());
-                    h = rotl31(h * M1) * M2;
-                    break;
-                },
-                _ => unsafe {
-                    let mut v1 = h;
-                    let mut v2 = Wrapping(self as u64);
-                    let mut v3 = Wrapping(self as u64);
-                    let mut v4 = Wrapping(self as u64);
-                    while len >= 32 {
-                        v1 ^= Wrapping((ptr as *const u64).read_unaligned());
-                        v1 = rotl31(v1 * M1) * M2;
-                        v2 ^= Wrapping((ptr.offset(8) as *const u64).read_unaligned());
-                        v2 = rotl31(v2 * M2) * M3;
-                        v3 ^= Wrapping((ptr.offset(16) as *const u64).read_unaligned());
-                        v3 = rotl31(v3 * M3) * M4;
-                        v4 ^= Wrapping((ptr.offset(24) as *const u64).read_unaligned());
-                        v4 = rotl31(v4 * M4) * M1;
-                        ptr = ptr.offset(32);
-                        len -= 32;
-                    }
-                    h = v1 ^ v2 ^ v3 ^ v4;
-                },
+    fn mem_hash_ignore_ascii_case<T: AsRef<[u8]>>(self, bytes: T) -> u32 {
+        hash(self, bytes.as_ref(), true)
+    }
+}
+
+#[inline(always)]
+fn hash(seed: u32, p: &[u8], fold: bool) -> u32 {
+    let mut ptr = p.as_ptr();
+    let mut len = p.len();
+    let mut h = Wrapping((seed as usize + len) as u64);
+
+    loop {
+        match len {
+            0 => break,
+            1..=3 => {
+                h ^= Wrapping(load_u8(p[0], fold));
+                h ^= Wrapping(load_u8(p[len >> 1], fold)) << 8;
+                h ^= Wrapping(load_u8(p[len - 1], fold)) << 16;
+                h = rotl31(h * M1) * M2;
+                break;
             }
+            4..=8 => unsafe {
+                h ^= Wrapping(load_u32(ptr, fold));
+                h ^= Wrapping(load_u32(ptr.add(len - 4), fold)) << 32;
+                h = rotl31(h * M1) * M2;
+                break;
+            },
+            9..=16 => unsafe {
+                h ^= Wrapping(load_u64(ptr, fold));
+                h = rotl31(h * M1) * M2;
+                h ^= Wrapping(load_u64(ptr.add(len - 8), fold));
+                h = rotl31(h * M1) * M2;
+                break;
+            },
+            17..=32 => unsafe {
+                h ^= Wrapping(load_u64(ptr, fold));
+                h = rotl31(h * M1) * M2;
+                h ^= Wrapping(load_u64(ptr.offset(8), fold));
+                h = rotl31(h * M1) * M2;
+                h ^= Wrapping(load_u64(ptr.add(len - 16), fold));
+                h = rotl31(h * M1) * M2;
+                h ^= Wrapping(load_u64(ptr.add(len - 8), fold));
+                h = rotl31(h * M1) * M2;
+                break;
+            },
+            _ => unsafe {
+                let mut v1 = h;
+                let mut v2 = Wrapping(seed as u64);
+                let mut v3 = Wrapping(seed as u64);
+                let mut v4 = Wrapping(seed as u64);
+                while len >= 32 {
+                    v1 ^= Wrapping(load_u64(ptr, fold));
+                    v1 = rotl31(v1 * M1) * M2;
+                    v2 ^= Wrapping(load_u64(ptr.offset(8), fold));
+                    v2 = rotl31(v2 * M2) * M3;
+                    v3 ^= Wrapping(load_u64(ptr.offset(16), fold));
+                    v3 = rotl31(v3 * M3) * M4;
+                    v4 ^= Wrapping(load_u64(ptr.offset(24), fold));
+                    v4 = rotl31(v4 * M4) * M1;
+                    ptr = ptr.offset(32);
+                    len -= 32;
+                }
+                h = v1 ^ v2 ^ v3 ^ v4;
+            },
         }
-        h ^= h >> 29;
-        h *= M3;
-        h ^= h >> 32;
-        h.0 as u32
     }
+    h ^= h >> 29;
+    h *= M3;
+    h ^= h >> 32;
+    h.0 as u32
 }
 
 #[test]
@@ -108,3 +159,22 @@ fn test_murmur_hash() {
         2880172322u32
     );
 }
+
+#[test]
+fn test_murmur_hash_ignore_ascii_case() {
+    for s in [
+        "A",
+        "aBc",
+        "ADSE",
+        "ADSeWhAt",
+        "IamWhatIAM.com",
+        "Hello, DomainMatcher!",
+        "The Quick Brown Fox Jumps Over The Lazy Dog [@`{]",
+        "\u{c4}\u{d6}\u{dc}.example.COM",
+    ] {
+        assert_eq!(
+            7u32.mem_hash_ignore_ascii_case(s),
+            7u32.mem_hash(s.to_ascii_lowercase())
+        );
+    }
+}
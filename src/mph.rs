@@ -1,80 +1,273 @@
-use crate::ac_automaton::ACAutomaton;
+use crate::ac_automaton::{ACAutomaton, ACAutomatonRef};
+use crate::index::{
+    decode_match_type, encode_match_type, open, seal, FormatError, Reader, StrTable, U32Array,
+    Writer, REMOVED,
+};
 use crate::mem_hash::MemHash;
-use crate::{DomainMatcher, MatchType};
+use crate::regex_matcher::{RegexMatcher, RegexTables};
+use crate::{
+    key_owner, live_rules, normalize_query, remove_rules, takes_key, BuildError, DomainMatcher,
+    MatchType, MatchedRule, Matches, Rule, NO_RULE,
+};
 use deepsize::DeepSizeOf;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
 use std::num::Wrapping;
 
 type RollingHashType = u32;
 type Level1HashType = u32;
 const PRIMEFK: Wrapping<RollingHashType> = Wrapping(16777619);
 const OFFSETS: Wrapping<RollingHashType> = Wrapping(0);
+const FLAG_CASE_SENSITIVE: u32 = 1;
+/// The number of seeds `MphMatcher::build` tries per bucket by default.
+pub const DEFAULT_SEED_LIMIT: u32 = 1 << 16;
+// how many times `build` doubles the tables before giving up
+const MAX_TABLE_GROWTH: usize = 4;
 
+#[inline(always)]
+fn fold(c: u8, case_sensitive: bool) -> u8 {
+    if case_sensitive {
+        c
+    } else {
+        c.to_ascii_lowercase()
+    }
+}
+
+// The rolling hash of a stored key, as computed by a reverse scan.
+fn rolling_hash(key: &str) -> RollingHashType {
+    let mut h = OFFSETS;
+    for c in key.bytes().rev() {
+        h = h * PRIMEFK + Wrapping(c as RollingHashType);
+    }
+    h.0
+}
+
+#[inline(always)]
+fn key_hash(seed: Level1HashType, query_string: &str, case_sensitive: bool) -> Level1HashType {
+    if case_sensitive {
+        seed.mem_hash(query_string)
+    } else {
+        seed.mem_hash_ignore_ascii_case(query_string)
+    }
+}
+
+// Stored keys are lowercase unless the matcher is case sensitive.
+#[inline(always)]
+fn key_eq(key: &[u8], query_string: &str, case_sensitive: bool) -> bool {
+    if case_sensitive {
+        key == query_string.as_bytes()
+    } else {
+        key.eq_ignore_ascii_case(query_string.as_bytes())
+    }
+}
+
+/// Matches Full and Domain rules through a minimal perfect hash table.
+///
+/// Keys inserted after `build()` wait in a small map, looked up when the
+/// table misses, until the next `build()` merges them into the table.
 #[derive(DeepSizeOf)]
-pub struct MphMatcher {
+pub struct MphMatcher<V = ()> {
     ac: ACAutomaton,
+    regex: RegexMatcher,
+    patterns: Vec<Rule<V>>,
     rules: Vec<String>,
+    rule_ids: Vec<u32>,
     level0: Vec<u32>,
     level0_mask: u32,
     level1: Vec<Level1HashType>,
     level1_mask: Level1HashType,
+    // keys inserted since the last `build()`, by rolling hash
+    pending: HashMap<RollingHashType, Vec<(String, u32)>>,
+    built: bool,
+    case_sensitive: bool,
+    seed_limit: u32,
 }
 
-impl DomainMatcher for MphMatcher {
-    fn reverse_insert(&mut self, input_string: &str, match_type: MatchType) {
+impl<V> DomainMatcher<V> for MphMatcher<V> {
+    fn reverse_insert(&mut self, input_string: &str, match_type: MatchType, value: V) {
+        let rule_id = self.patterns.len() as u32;
+        self.patterns
+            .push(Rule::new(input_string, match_type, value));
         match match_type {
-            MatchType::SubStr(_) => self.ac.reverse_insert(input_string, match_type),
+            MatchType::SubStr(_) => self.ac.insert_with_id(input_string, match_type, rule_id),
             MatchType::Domain(_) => {
-                self.insert_rules(input_string.to_string());
-                self.insert_rules(format!(".{}", input_string));
+                let key = self.key(input_string);
+                self.insert_rules(format!(".{}", key), rule_id);
+                self.insert_rules(key, rule_id);
             }
             MatchType::Full(_) => {
-                self.insert_rules(input_string.to_string());
+                let key = self.key(input_string);
+                self.insert_rules(key, rule_id);
             }
+            MatchType::Regex(_) => self.regex.insert(input_string, rule_id),
         }
     }
 
+    fn remove(&mut self, pattern: &str, match_type: MatchType) -> bool {
+        let ids = remove_rules(&mut self.patterns, pattern, match_type, self.case_sensitive);
+        for id in ids.iter() {
+            match match_type {
+                MatchType::SubStr(_) => self.ac.remove_with_id(
+                    pattern,
+                    match_type,
+                    *id,
+                    live_rules(&self.patterns)
+                        .filter(|(_, rule)| matches!(rule.match_type, MatchType::SubStr(_))),
+                ),
+                MatchType::Domain(_) => {
+                    let key = self.key(pattern);
+                    self.unlink(format!(".{}", key), *id);
+                    self.unlink(key, *id);
+                }
+                MatchType::Full(_) => self.unlink(self.key(pattern), *id),
+                MatchType::Regex(_) => self.regex.remove(*id),
+            }
+        }
+        !ids.is_empty()
+    }
+
     fn reverse_query(&self, query_string: &str) -> bool {
+        self.matched_rule_id(query_string).is_some()
+    }
+
+    fn reverse_query_rule(&self, query_string: &str) -> Option<MatchedRule<'_, V>> {
+        self.matched_rule_id(query_string)
+            .map(|id| self.patterns[id as usize].matched(id as usize))
+    }
+
+    fn reverse_query_all(&self, query_string: &str) -> Matches<'_, V> {
+        let query_string = match normalize_query(query_string) {
+            Some(query_string) => query_string,
+            None => return Matches::new(&self.patterns, Vec::new()),
+        };
+        let mut rule_ids = Vec::new();
         let mut h = OFFSETS;
         let mut idx = Wrapping(query_string.len() - 1);
         for c in query_string.bytes().rev() {
-            h = h * PRIMEFK + Wrapping(c as RollingHashType);
+            h = h * PRIMEFK + Wrapping(fold(c, self.case_sensitive) as RollingHashType);
             if c == b'.' {
-                if self.lookup(h.0, &query_string[idx.0..]) {
-                    return true;
+                if let Some(id) = self.lookup(h.0, &query_string[idx.0..]) {
+                    rule_ids.push(id);
                 }
             }
             idx -= Wrapping(1);
         }
-        if self.lookup(h.0, query_string) {
-            return true;
-        } else {
-            if !self.ac.empty() {
-                self.ac.reverse_query(query_string)
-            } else {
-                false
-            }
+        if let Some(id) = self.lookup(h.0, query_string) {
+            rule_ids.push(id);
+        }
+        rule_ids.reverse();
+        if !self.ac.empty() {
+            self.ac.collect_rule_ids(query_string, &mut rule_ids);
+        }
+        if !self.regex.is_empty() {
+            self.regex.collect_rule_ids(
+                query_string,
+                |id| self.patterns[id as usize].match_type.is_exception(),
+                &mut rule_ids,
+            );
         }
+        Matches::new(&self.patterns, rule_ids)
     }
 
-    fn build(&mut self) {
-        if !self.ac.empty() {
-            self.ac.build()
+    fn build(&mut self) -> Result<(), BuildError> {
+        self.ac.build()?;
+        // a Regex pattern that fails is reported once the tables are built
+        let regex = self.regex.build();
+        self.built = true;
+        for (key, rule_id) in self.pending.drain().flat_map(|(_, keys)| keys) {
+            if let Err(pos) = self.rules.binary_search(&key) {
+                self.rules.insert(pos, key);
+                self.rule_ids.insert(pos, rule_id);
+            }
         }
+        let (rules, rule_ids) = std::mem::take(&mut self.rules)
+            .into_iter()
+            .zip(std::mem::take(&mut self.rule_ids))
+            .filter(|(_, rule_id)| *rule_id != NO_RULE)
+            .unzip();
+        self.rules = rules;
+        self.rule_ids = rule_ids;
         let size = self.rules.len();
-        let level0_size = (size / 4).next_power_of_two();
-        let level1_size = size.next_power_of_two();
+        if size > Level1HashType::MAX as usize {
+            return Err(BuildError::TooManyRules(size));
+        }
+        // A larger table leaves more free slots and splits the level0
+        // buckets, so a seed is found sooner.
+        for growth in 0..=MAX_TABLE_GROWTH {
+            let level0_size = (size / 4).next_power_of_two() << growth;
+            let level1_size = size.next_power_of_two() << growth;
+            if self.place(level0_size, level1_size) {
+                return regex;
+            }
+        }
+        Err(BuildError::SeedLimitExceeded(self.seed_limit))
+    }
+
+    fn clear(&mut self) {
+        self.ac.clear();
+        self.regex.clear();
+        self.patterns.clear();
+        self.rules.clear();
+        self.rule_ids.clear();
+        self.level0.clear();
+        self.level1.clear();
+        self.level0_mask = 0;
+        self.level1_mask = 0;
+        self.pending.clear();
+        self.built = false;
+    }
+}
+
+impl<V> MphMatcher<V> {
+    /// Creates a matcher that ignores ASCII case.
+    pub fn new(size: usize) -> MphMatcher<V> {
+        MphMatcher::with_case(size, false)
+    }
+
+    /// Creates a matcher that tells ASCII upper and lower case apart.
+    pub fn new_case_sensitive(size: usize) -> MphMatcher<V> {
+        MphMatcher::with_case(size, true)
+    }
+
+    fn with_case(size: usize, case_sensitive: bool) -> MphMatcher<V> {
+        MphMatcher {
+            ac: ACAutomaton::with_case(size, case_sensitive),
+            regex: RegexMatcher::new(case_sensitive),
+            patterns: Vec::new(),
+            rules: Vec::new(),
+            rule_ids: Vec::new(),
+            level0: Vec::new(),
+            level0_mask: 0,
+            level1: Vec::new(),
+            level1_mask: 0,
+            pending: HashMap::new(),
+            built: false,
+            case_sensitive,
+            seed_limit: DEFAULT_SEED_LIMIT,
+        }
+    }
+
+    pub fn is_case_sensitive(&self) -> bool {
+        self.case_sensitive
+    }
+
+    /// Sets how many seeds `build()` tries for a level0 bucket before it
+    /// retries with larger tables. Defaults to `DEFAULT_SEED_LIMIT`.
+    pub fn set_seed_limit(&mut self, seed_limit: u32) {
+        self.seed_limit = seed_limit;
+    }
+
+    // Fills tables of the given sizes, returning false if a bucket needs a
+    // seed at or above `seed_limit`.
+    fn place(&mut self, level0_size: usize, level1_size: usize) -> bool {
         self.level0 = vec![0; level0_size];
         self.level1 = vec![0; level1_size];
         self.level0_mask = (level0_size - 1) as u32;
         self.level1_mask = (level1_size - 1) as Level1HashType;
         let mut sparse_bucket: Vec<Vec<u32>> = vec![Vec::new(); level0_size];
         for (idx, rule) in self.rules.iter().enumerate() {
-            let mut h = OFFSETS;
-            for c in rule.bytes().rev() {
-                h = h * PRIMEFK + Wrapping(c as RollingHashType);
-            }
-            h &= Wrapping(self.level0_mask);
-            sparse_bucket[h.0 as usize].push(idx as u32);
+            let h = rolling_hash(rule) & self.level0_mask;
+            sparse_bucket[h as usize].push(idx as u32);
         }
         let mut buckets: Vec<(usize, &Vec<u32>)> = Vec::new();
         for (level0_idx, val) in sparse_bucket.iter().enumerate() {
@@ -82,12 +275,15 @@ impl DomainMatcher for MphMatcher {
                 buckets.push((level0_idx, val));
             }
         }
-        buckets.sort_by(|x, y| y.1.len().cmp(&x.1.len()));
+        buckets.sort_by_key(|x| std::cmp::Reverse(x.1.len()));
         let mut occ = vec![false; level1_size];
         let mut tmp_occ: Vec<usize> = Vec::new();
         for bucket in buckets {
             let mut seed: Level1HashType = 0;
             loop {
+                if seed >= self.seed_limit {
+                    return false;
+                }
                 tmp_occ.clear();
                 let mut find_seed = true;
                 for rule_idx in bucket.1 {
@@ -106,48 +302,562 @@ impl DomainMatcher for MphMatcher {
                     self.level1[level1_idx as usize] = *rule_idx as Level1HashType;
                 }
                 if find_seed {
-                    self.level0[bucket.0 as usize] = seed as u32;
+                    self.level0[bucket.0] = seed as u32;
                     break;
                 }
             }
         }
+        true
     }
-    fn clear(&mut self) {
-        self.ac.clear();
-        self.rules.clear();
-        self.level0.clear();
-        self.level1.clear();
-        self.level0_mask = 0;
-        self.level1_mask = 0;
-    }
-}
 
-impl MphMatcher {
-    pub fn new(size: usize) -> MphMatcher {
-        MphMatcher {
-            ac: ACAutomaton::new(size),
-            rules: Vec::new(),
-            level0: Vec::new(),
-            level0_mask: 0,
-            level1: Vec::new(),
-            level1_mask: 0,
+    // Hash keys are stored lowercase unless the matcher is case sensitive.
+    fn key(&self, pattern: &str) -> String {
+        if self.case_sensitive {
+            pattern.to_string()
+        } else {
+            pattern.to_ascii_lowercase()
         }
     }
 
-    fn insert_rules(&mut self, pattern: String) {
+    // Once built, `rules` must keep its order, so new keys wait in `pending`
+    // for the next `build()`.
+    fn insert_rules(&mut self, pattern: String, rule_id: u32) {
         match self.rules.binary_search(&pattern) {
-            Err(pos) => {
+            Ok(pos) => {
+                let old = self.rule_ids[pos];
+                if old == NO_RULE || takes_key(&self.patterns, old, rule_id) {
+                    self.rule_ids[pos] = rule_id;
+                }
+            }
+            Err(pos) if !self.built => {
                 self.rules.insert(pos, pattern);
+                self.rule_ids.insert(pos, rule_id);
             }
+            Err(_) => {
+                let keys = self.pending.entry(rolling_hash(&pattern)).or_default();
+                match keys.iter_mut().find(|(key, _)| *key == pattern) {
+                    Some(old) if takes_key(&self.patterns, old.1, rule_id) => old.1 = rule_id,
+                    Some(_) => {}
+                    None => keys.push((pattern, rule_id)),
+                }
+            }
+        }
+    }
+
+    // Hands `key` from the removed rule `id` to the first live rule owning
+    // it, or marks it unused if there is none.
+    fn unlink(&mut self, key: String, id: u32) {
+        let next = key_owner(&self.patterns, &key, self.case_sensitive);
+        if let Ok(pos) = self.rules.binary_search(&key) {
+            if self.rule_ids[pos] == id {
+                self.rule_ids[pos] = next.unwrap_or(NO_RULE);
+            }
+            return;
+        }
+        let h = rolling_hash(&key);
+        if let Some(keys) = self.pending.get_mut(&h) {
+            if let Some(pos) = keys
+                .iter()
+                .position(|(x, rule_id)| *x == key && *rule_id == id)
+            {
+                match next {
+                    Some(next) => keys[pos].1 = next,
+                    None => {
+                        keys.remove(pos);
+                    }
+                }
+            }
+            if keys.is_empty() {
+                self.pending.remove(&h);
+            }
+        }
+    }
+
+    /// Returns the rule deciding `query_string`, which may be an exception.
+    fn query_rule_id(&self, query_string: &str) -> Option<u32> {
+        // the longest matching suffix is the most specific rule
+        let mut found = None;
+        let mut h = OFFSETS;
+        let mut idx = Wrapping(query_string.len() - 1);
+        for c in query_string.bytes().rev() {
+            h = h * PRIMEFK + Wrapping(fold(c, self.case_sensitive) as RollingHashType);
+            if c == b'.' {
+                if let Some(id) = self.lookup(h.0, &query_string[idx.0..]) {
+                    found = Some(id);
+                }
+            }
+            idx -= Wrapping(1);
+        }
+        if let Some(id) = self.lookup(h.0, query_string) {
+            return Some(id);
+        }
+        if found.is_some() {
+            return found;
+        }
+        self.fallback_rule_id(query_string)
+    }
+
+    // The SubStr and Regex stages of `query_rule_id`, for a query no Full or
+    // Domain rule decides.
+    fn fallback_rule_id(&self, query_string: &str) -> Option<u32> {
+        if !self.ac.empty() {
+            if let Some(id) = self.ac.query_rule_id(query_string) {
+                return Some(id);
+            }
+        }
+        if !self.regex.is_empty() {
+            self.regex.query_rule_id(query_string, |id| {
+                self.patterns[id as usize].match_type.is_exception()
+            })
+        } else {
+            None
+        }
+    }
+
+    fn matched_rule_id(&self, query_string: &str) -> Option<u32> {
+        self.query_rule_id(normalize_query(query_string)?)
+            .filter(|id| !self.patterns[*id as usize].match_type.is_exception())
+    }
+
+    /// Calls `f` on every rule matching `query_string`, collected in a single
+    /// reverse scan. A SubStr rule is reported once per occurrence.
+    /// `query_string` is normalized first.
+    pub(crate) fn for_each_match<'a, F: FnMut(MatchedRule<'a, V>)>(
+        &'a self,
+        query_string: &str,
+        mut f: F,
+    ) {
+        let query_string = match normalize_query(query_string) {
+            Some(query_string) => query_string,
+            None => return,
+        };
+        let mut h = OFFSETS;
+        let mut idx = Wrapping(query_string.len() - 1);
+        for c in query_string.bytes().rev() {
+            h = h * PRIMEFK + Wrapping(fold(c, self.case_sensitive) as RollingHashType);
+            if c == b'.' {
+                if let Some(id) = self.lookup(h.0, &query_string[idx.0..]) {
+                    f(self.patterns[id as usize].matched(id as usize));
+                }
+            }
+            idx -= Wrapping(1);
+        }
+        if let Some(id) = self.lookup(h.0, query_string) {
+            f(self.patterns[id as usize].matched(id as usize));
+        }
+        if !self.ac.empty() {
+            self.ac.for_each_rule_id(query_string, |id| {
+                f(self.patterns[id as usize].matched(id as usize))
+            });
+        }
+        if !self.regex.is_empty() {
+            self.regex.for_each_rule_id(query_string, |id| {
+                f(self.patterns[id as usize].matched(id as usize))
+            });
+        }
+    }
+
+    #[inline(always)]
+    fn lookup(&self, h: RollingHashType, query_string: &str) -> Option<u32> {
+        let level0_idx = h & self.level0_mask;
+        let seed = match self.level0.get(level0_idx as usize) {
+            Some(seed) => *seed as Level1HashType,
+            // not built yet, or cleared: only `pending` can hold the key
+            None => return self.resolve(h, usize::MAX, query_string),
+        };
+        let level1_idx = key_hash(seed, query_string, self.case_sensitive) & self.level1_mask;
+        let rule_idx = self.level1[level1_idx as usize] as usize;
+        self.resolve(h, rule_idx, query_string)
+    }
+
+    // The second half of `lookup`: checks the key in slot `rule_idx`, then
+    // the keys inserted since the last `build()`.
+    #[inline(always)]
+    fn resolve(&self, h: RollingHashType, rule_idx: usize, query_string: &str) -> Option<u32> {
+        // `rules` is empty when only SubStr or Regex rules were inserted.
+        match self.rules.get(rule_idx) {
+            Some(rule) if key_eq(rule.as_bytes(), query_string, self.case_sensitive) => {
+                return Some(self.rule_ids[rule_idx]).filter(|id| *id != NO_RULE);
+            }
+            _ if self.pending.is_empty() => return None,
             _ => {}
         }
+        self.pending
+            .get(&h)?
+            .iter()
+            .find(|(key, _)| key_eq(key.as_bytes(), query_string, self.case_sensitive))
+            .map(|(_, rule_id)| *rule_id)
+    }
+}
+
+// How many queries `reverse_query_batch` hashes before it looks any of them
+// up, so that their table loads overlap.
+const BATCH_LANES: usize = 16;
+// The smallest piece of a batch that `par_reverse_query_batch` hands to one
+// thread.
+#[cfg(feature = "rayon")]
+const PAR_CHUNK_LEN: usize = 4096;
+
+// A candidate key of a batched query: the query itself or one of its
+// suffixes starting at a dot.
+struct Probe<'q> {
+    lane: usize,
+    key: &'q str,
+    h: RollingHashType,
+    // the level0 seed, then the level1 slot
+    slot: Level1HashType,
+    full: bool,
+}
+
+impl<V> MphMatcher<V> {
+    /// Answers `reverse_query` for every query in `queries`, in order.
+    ///
+    /// The queries are taken in groups: the rolling hashes of a whole group
+    /// are computed first, then its `level0` loads, then its `level1` loads,
+    /// so that the cache misses of different queries overlap instead of
+    /// following one another.
+    pub fn reverse_query_batch(&self, queries: &[&str]) -> Vec<bool> {
+        let mut rule_ids = Vec::with_capacity(queries.len());
+        self.matched_rule_ids(queries, &mut rule_ids);
+        rule_ids.into_iter().map(|id| id.is_some()).collect()
+    }
+
+    /// Like `reverse_query_batch`, but returns the `index` of the rule that
+    /// `reverse_query_rule` reports for each query.
+    pub fn reverse_query_index_batch(&self, queries: &[&str]) -> Vec<Option<usize>> {
+        let mut rule_ids = Vec::with_capacity(queries.len());
+        self.matched_rule_ids(queries, &mut rule_ids);
+        rule_ids
+            .into_iter()
+            .map(|id| id.map(|id| id as usize))
+            .collect()
+    }
+
+    // Appends `matched_rule_id` of every query to `out`.
+    fn matched_rule_ids(&self, queries: &[&str], out: &mut Vec<Option<u32>>) {
+        if self.level0.is_empty() {
+            out.extend(
+                queries
+                    .iter()
+                    .map(|query_string| self.matched_rule_id(query_string)),
+            );
+            return;
+        }
+        let mut probes = Vec::new();
+        for chunk in queries.chunks(BATCH_LANES) {
+            let mut lanes = [None; BATCH_LANES];
+            probes.clear();
+            for (lane, query_string) in chunk.iter().enumerate() {
+                lanes[lane] = normalize_query(query_string);
+                let query_string = match lanes[lane] {
+                    Some(query_string) => query_string,
+                    None => continue,
+                };
+                let mut h = OFFSETS;
+                let mut idx = Wrapping(query_string.len() - 1);
+                for c in query_string.bytes().rev() {
+                    h = h * PRIMEFK + Wrapping(fold(c, self.case_sensitive) as RollingHashType);
+                    if c == b'.' {
+                        probes.push(Probe {
+                            lane,
+                            key: &query_string[idx.0..],
+                            h: h.0,
+                            slot: 0,
+                            full: false,
+                        });
+                    }
+                    idx -= Wrapping(1);
+                }
+                probes.push(Probe {
+                    lane,
+                    key: query_string,
+                    h: h.0,
+                    slot: 0,
+                    full: true,
+                });
+            }
+            for probe in probes.iter_mut() {
+                probe.slot = self.level0[(probe.h & self.level0_mask) as usize];
+            }
+            for probe in probes.iter_mut() {
+                let level1_idx =
+                    key_hash(probe.slot, probe.key, self.case_sensitive) & self.level1_mask;
+                probe.slot = self.level1[level1_idx as usize];
+            }
+            // Same precedence as `query_rule_id`: the whole name, then the
+            // longest suffix, which comes last in a lane.
+            let mut exact = [None; BATCH_LANES];
+            let mut suffix = [None; BATCH_LANES];
+            for probe in probes.iter() {
+                if let Some(id) = self.resolve(probe.h, probe.slot as usize, probe.key) {
+                    if probe.full {
+                        exact[probe.lane] = Some(id);
+                    } else {
+                        suffix[probe.lane] = Some(id);
+                    }
+                }
+            }
+            for (lane, query_string) in lanes.iter().take(chunk.len()).enumerate() {
+                let id = query_string.and_then(|query_string| {
+                    exact[lane]
+                        .or(suffix[lane])
+                        .or_else(|| self.fallback_rule_id(query_string))
+                });
+                out.push(id.filter(|id| !self.patterns[*id as usize].match_type.is_exception()));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<V: Sync> MphMatcher<V> {
+    /// Like `reverse_query_batch`, but splits a large batch across the rayon
+    /// thread pool.
+    pub fn par_reverse_query_batch(&self, queries: &[&str]) -> Vec<bool> {
+        self.par_matched_rule_ids(queries)
+            .into_iter()
+            .map(|id| id.is_some())
+            .collect()
+    }
+
+    /// Like `reverse_query_index_batch`, but splits a large batch across the
+    /// rayon thread pool.
+    pub fn par_reverse_query_index_batch(&self, queries: &[&str]) -> Vec<Option<usize>> {
+        self.par_matched_rule_ids(queries)
+            .into_iter()
+            .map(|id| id.map(|id| id as usize))
+            .collect()
+    }
+
+    fn par_matched_rule_ids(&self, queries: &[&str]) -> Vec<Option<u32>> {
+        use rayon::prelude::*;
+
+        queries
+            .par_chunks(PAR_CHUNK_LEN)
+            .flat_map_iter(|chunk| {
+                let mut rule_ids = Vec::with_capacity(chunk.len());
+                self.matched_rule_ids(chunk, &mut rule_ids);
+                rule_ids
+            })
+            .collect()
+    }
+}
+
+impl MphMatcher {
+    /// Writes a built matcher to `writer` in the format described in
+    /// [`index`](crate::index), so that it can be loaded without `build()`.
+    /// Fails with `InvalidInput` if the matcher was never built, or while Full
+    /// or Domain rules inserted since the last `build()` wait to be merged
+    /// into the tables.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        if !self.built {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "matcher is not built",
+            ));
+        }
+        if !self.pending.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "matcher has rules inserted since the last build()",
+            ));
+        }
+        let mut w = Writer::new();
+        w.u32(if self.case_sensitive {
+            FLAG_CASE_SENSITIVE
+        } else {
+            0
+        });
+        w.u32(self.level0_mask);
+        w.u32(self.level1_mask);
+        w.u32s(self.level0.iter().copied());
+        w.u32s(self.level1.iter().copied());
+        w.strs(self.rules.iter().map(|s| s.as_str()));
+        w.u32s(self.rule_ids.iter().copied());
+        w.strs(self.patterns.iter().map(|rule| rule.pattern.as_str()));
+        w.u32s(self.patterns.iter().map(|rule| {
+            encode_match_type(rule.match_type) | if rule.removed { REMOVED } else { 0 }
+        }));
+        self.ac.write_tables(&mut w);
+        self.regex.write_tables(&mut w);
+        writer.write_all(&seal(w.into_inner()))
+    }
+
+    /// Reads a matcher written by `write_to`. Regex rules are recompiled, the
+    /// rest is used as is.
+    pub fn read_from<R: Read>(reader: &mut R) -> Result<MphMatcher, FormatError> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let (view, regex) = MphMatcherRef::parse(&bytes)?;
+        let mut matcher = MphMatcher::with_case(1, view.case_sensitive);
+        matcher.ac = ACAutomaton::read_tables(&mut Reader::new(view.ac_bytes))?;
+        matcher.regex = regex.to_matcher(view.case_sensitive);
+        for (pattern, match_type) in view.patterns.iter().zip(view.match_types.iter()) {
+            let mut rule = Rule::new(pattern, decode_match_type(match_type)?, ());
+            rule.removed = match_type & REMOVED != 0;
+            matcher.patterns.push(rule);
+        }
+        matcher.rules = view.rules.iter().map(|s| s.to_string()).collect();
+        matcher.rule_ids = view.rule_ids.iter().collect();
+        matcher.level0 = view.level0.iter().collect();
+        matcher.level0_mask = view.level0_mask;
+        matcher.level1 = view.level1.iter().collect();
+        matcher.level1_mask = view.level1_mask;
+        matcher.built = true;
+        Ok(matcher)
+    }
+}
+
+/// A read-only `MphMatcher` that queries a serialized index in place.
+///
+/// It borrows the bytes written by `MphMatcher::write_to`, e.g. from a shared
+/// memory map, and neither copies them nor allocates while querying. Regex
+/// rules need compiling, so indexes holding them are rejected; load those with
+/// `MphMatcher::read_from` instead.
+#[derive(Copy, Clone)]
+pub struct MphMatcherRef<'a> {
+    ac: ACAutomatonRef<'a>,
+    ac_bytes: &'a [u8],
+    patterns: StrTable<'a>,
+    match_types: U32Array<'a>,
+    rules: StrTable<'a>,
+    rule_ids: U32Array<'a>,
+    level0: U32Array<'a>,
+    level0_mask: u32,
+    level1: U32Array<'a>,
+    level1_mask: Level1HashType,
+    case_sensitive: bool,
+}
+
+impl<'a> MphMatcherRef<'a> {
+    /// Checks the header, checksum and table bounds of `bytes`. This reads
+    /// the whole index once but does not allocate.
+    pub fn new(bytes: &'a [u8]) -> Result<MphMatcherRef<'a>, FormatError> {
+        let (view, regex) = MphMatcherRef::parse(bytes)?;
+        if regex.patterns.len() != 0 {
+            return Err(FormatError::Unsupported("regex rules"));
+        }
+        Ok(view)
+    }
+
+    fn parse(bytes: &'a [u8]) -> Result<(MphMatcherRef<'a>, RegexTables<'a>), FormatError> {
+        let mut r = Reader::new(open(bytes)?);
+        let flags = r.u32()?;
+        let case_sensitive = flags & FLAG_CASE_SENSITIVE != 0;
+        let level0_mask = r.u32()?;
+        let level1_mask = r.u32()?;
+        let level0 = r.u32s()?;
+        let level1 = r.u32s()?;
+        let rules = r.strs()?;
+        let rule_ids = r.u32s()?;
+        let patterns = r.strs()?;
+        let match_types = r.u32s()?;
+        let ac_start = r.remaining();
+        let ac = ACAutomatonRef::read(&mut r)?;
+        let ac_bytes = &ac_start[..ac_start.len() - r.remaining().len()];
+        let regex = RegexTables::read(&mut r)?;
+        r.finish()?;
+
+        if flags & !FLAG_CASE_SENSITIVE != 0
+            || ac.case_sensitive() != case_sensitive
+            || level0.len() != level0_mask as usize + 1
+            || level1.len() != level1_mask as usize + 1
+            || level1.iter().any(|v| v as usize >= rules.len().max(1))
+            || rule_ids.len() != rules.len()
+            || match_types.len() != patterns.len()
+            || match_types.iter().any(|v| decode_match_type(v).is_err())
+            || rule_ids
+                .iter()
+                .filter(|id| *id != NO_RULE)
+                .chain(ac.rule_ids())
+                .chain(regex.rule_ids.iter())
+                .any(|id| id as usize >= patterns.len())
+        {
+            return Err(FormatError::Corrupted);
+        }
+        let view = MphMatcherRef {
+            ac,
+            ac_bytes,
+            patterns,
+            match_types,
+            rules,
+            rule_ids,
+            level0,
+            level0_mask,
+            level1,
+            level1_mask,
+            case_sensitive,
+        };
+        Ok((view, regex))
+    }
+
+    pub fn reverse_query(&self, query_string: &str) -> bool {
+        self.matched_rule_id(query_string).is_some()
+    }
+
+    pub fn reverse_query_rule(&self, query_string: &str) -> Option<MatchedRule<'a>> {
+        self.matched_rule_id(query_string).map(|id| MatchedRule {
+            pattern: self.patterns.get(id as usize),
+            match_type: self.match_type(id),
+            index: id as usize,
+            value: &(),
+        })
+    }
+
+    #[inline(always)]
+    fn match_type(&self, id: u32) -> MatchType {
+        // `parse` checked every match type.
+        decode_match_type(self.match_types.get(id as usize)).unwrap_or(MatchType::Full(true))
+    }
+
+    /// Same as `MphMatcher::query_rule_id`.
+    fn query_rule_id(&self, query_string: &str) -> Option<u32> {
+        let mut found = None;
+        let mut h = OFFSETS;
+        let mut idx = Wrapping(query_string.len() - 1);
+        for c in query_string.bytes().rev() {
+            h = h * PRIMEFK + Wrapping(fold(c, self.case_sensitive) as RollingHashType);
+            if c == b'.' {
+                if let Some(id) = self.lookup(h.0, &query_string[idx.0..]) {
+                    found = Some(id);
+                }
+            }
+            idx -= Wrapping(1);
+        }
+        if let Some(id) = self.lookup(h.0, query_string) {
+            return Some(id);
+        }
+        if found.is_some() {
+            return found;
+        }
+        if !self.ac.empty() {
+            self.ac.query_rule_id(query_string)
+        } else {
+            None
+        }
+    }
+
+    fn matched_rule_id(&self, query_string: &str) -> Option<u32> {
+        self.query_rule_id(normalize_query(query_string)?)
+            .filter(|id| !self.match_type(*id).is_exception())
     }
 
     #[inline(always)]
-    fn lookup(&self, h: RollingHashType, query_string: &str) -> bool {
+    fn lookup(&self, h: RollingHashType, query_string: &str) -> Option<u32> {
         let level0_idx = h & self.level0_mask;
-        let seed = self.level0[level0_idx as usize] as Level1HashType;
-        let level1_idx = seed.mem_hash(query_string) & self.level1_mask;
-        return self.rules[self.level1[level1_idx as usize] as usize] == query_string;
+        let seed = self.level0.get(level0_idx as usize) as Level1HashType;
+        let level1_idx = key_hash(seed, query_string, self.case_sensitive) & self.level1_mask;
+        let rule_idx = self.level1.get(level1_idx as usize) as usize;
+        if rule_idx < self.rules.len()
+            && key_eq(
+                self.rules.get_bytes(rule_idx),
+                query_string,
+                self.case_sensitive,
+            )
+        {
+            Some(self.rule_ids.get(rule_idx)).filter(|id| *id != NO_RULE)
+        } else {
+            None
+        }
     }
 }
@@ -0,0 +1,190 @@
+use crate::index::{FormatError, Reader, StrTable, U32Array, Writer};
+use crate::BuildError;
+use deepsize::{Context, DeepSizeOf};
+use regex::{RegexSet, RegexSetBuilder};
+
+/// The regex stage of a matcher, consulted after the hash and AC stages.
+///
+/// Patterns are compiled into a single `RegexSet` by `build()`, which reports
+/// the first pattern that fails to compile or to fit the set. That pattern and
+/// the ones after it that do not fit never match. Once built, every insert or
+/// remove compiles the set again, and the next `build()` reports a failure.
+pub(crate) struct RegexMatcher {
+    case_sensitive: bool,
+    built: bool,
+    patterns: Vec<String>,
+    rule_ids: Vec<u32>,
+    set: RegexSet,
+    set_ids: Vec<u32>,
+}
+
+impl DeepSizeOf for RegexMatcher {
+    fn deep_size_of_children(&self, context: &mut Context) -> usize {
+        self.patterns.deep_size_of_children(context)
+            + self.rule_ids.deep_size_of_children(context)
+            + self.set_ids.deep_size_of_children(context)
+    }
+}
+
+impl RegexMatcher {
+    pub(crate) fn new(case_sensitive: bool) -> RegexMatcher {
+        RegexMatcher {
+            case_sensitive,
+            built: false,
+            patterns: Vec::new(),
+            rule_ids: Vec::new(),
+            set: RegexSet::empty(),
+            set_ids: Vec::new(),
+        }
+    }
+
+    pub(crate) fn insert(&mut self, pattern: &str, rule_id: u32) {
+        self.patterns.push(pattern.to_string());
+        self.rule_ids.push(rule_id);
+        if self.built {
+            let _ = self.build();
+        }
+    }
+
+    pub(crate) fn remove(&mut self, rule_id: u32) {
+        if let Some(idx) = self.rule_ids.iter().position(|id| *id == rule_id) {
+            self.patterns.remove(idx);
+            self.rule_ids.remove(idx);
+            if self.built {
+                let _ = self.build();
+            }
+        }
+    }
+
+    pub(crate) fn build(&mut self) -> Result<(), BuildError> {
+        self.built = true;
+        if let Ok(set) = compile(&self.patterns, self.case_sensitive) {
+            self.set = set;
+            self.set_ids = self.rule_ids.clone();
+            return Ok(());
+        }
+        let compiles = |idx: &usize| compile([&self.patterns[*idx]], self.case_sensitive).is_ok();
+        let (mut valid, invalid): (Vec<usize>, Vec<usize>) =
+            (0..self.patterns.len()).partition(compiles);
+        // Each valid pattern fits on its own, but together they may not: keep
+        // the longest prefix of them that does.
+        let set_of = |valid: &[usize]| {
+            compile(
+                valid.iter().map(|idx| &self.patterns[*idx]),
+                self.case_sensitive,
+            )
+        };
+        let mut oversize = None;
+        if set_of(&valid).is_err() {
+            let (mut fits, mut fails) = (0, valid.len());
+            while fails - fits > 1 {
+                let mid = (fits + fails) / 2;
+                if set_of(&valid[..mid]).is_ok() {
+                    fits = mid;
+                } else {
+                    fails = mid;
+                }
+            }
+            oversize = Some(valid[fits]);
+            valid.truncate(fits);
+        }
+        self.set = set_of(&valid).unwrap_or_else(|_| RegexSet::empty());
+        self.set_ids = valid.iter().map(|idx| self.rule_ids[*idx]).collect();
+        let failed = invalid.first().copied().or(oversize).unwrap_or(0);
+        Err(BuildError::InvalidRegex(self.patterns[failed].clone()))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Returns the rule deciding `query_string`: the earliest inserted
+    /// exception matching it, or else the earliest inserted rule matching it.
+    pub(crate) fn query_rule_id<F: Fn(u32) -> bool>(
+        &self,
+        query_string: &str,
+        is_exception: F,
+    ) -> Option<u32> {
+        let mut found = None;
+        for idx in self.set.matches(query_string).iter() {
+            let id = self.set_ids[idx];
+            if is_exception(id) {
+                return Some(id);
+            }
+            found.get_or_insert(id);
+        }
+        found
+    }
+
+    pub(crate) fn for_each_rule_id<F: FnMut(u32)>(&self, query_string: &str, mut f: F) {
+        for idx in self.set.matches(query_string).iter() {
+            f(self.set_ids[idx]);
+        }
+    }
+
+    /// Appends the id of every rule matching `query_string` to `out`,
+    /// exceptions first and otherwise in insertion order.
+    pub(crate) fn collect_rule_ids<F: Fn(u32) -> bool>(
+        &self,
+        query_string: &str,
+        is_exception: F,
+        out: &mut Vec<u32>,
+    ) {
+        let start = out.len();
+        self.for_each_rule_id(query_string, |id| out.push(id));
+        out[start..].sort_by_key(|id| !is_exception(*id));
+    }
+
+    pub(crate) fn write_tables(&self, w: &mut Writer) {
+        w.strs(self.patterns.iter().map(|s| s.as_str()));
+        w.u32s(self.rule_ids.iter().copied());
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.built = false;
+        self.patterns.clear();
+        self.rule_ids.clear();
+        self.set = RegexSet::empty();
+        self.set_ids.clear();
+    }
+}
+
+fn compile<I, S>(patterns: I, case_sensitive: bool) -> Result<RegexSet, regex::Error>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    RegexSetBuilder::new(patterns)
+        .case_insensitive(!case_sensitive)
+        .build()
+}
+
+/// The regex tables written by `RegexMatcher::write_tables`.
+pub(crate) struct RegexTables<'a> {
+    pub(crate) patterns: StrTable<'a>,
+    pub(crate) rule_ids: U32Array<'a>,
+}
+
+impl<'a> RegexTables<'a> {
+    pub(crate) fn read(r: &mut Reader<'a>) -> Result<RegexTables<'a>, FormatError> {
+        let tables = RegexTables {
+            patterns: r.strs()?,
+            rule_ids: r.u32s()?,
+        };
+        if tables.patterns.len() != tables.rule_ids.len() {
+            return Err(FormatError::Corrupted);
+        }
+        Ok(tables)
+    }
+
+    /// Compiles the patterns into a `RegexMatcher`.
+    pub(crate) fn to_matcher(&self, case_sensitive: bool) -> RegexMatcher {
+        let mut regex = RegexMatcher::new(case_sensitive);
+        for (pattern, rule_id) in self.patterns.iter().zip(self.rule_ids.iter()) {
+            regex.insert(pattern, rule_id);
+        }
+        // a pattern that failed when the index was written fails again
+        let _ = regex.build();
+        regex
+    }
+}
@@ -0,0 +1,66 @@
+//! Hot reloading of rule sets under concurrent readers.
+use crate::mph::MphMatcher;
+use crate::DomainMatcher;
+use arc_swap::{ArcSwap, Guard};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+/// A matcher that can be swapped for a newly built one while other threads
+/// query it.
+///
+/// Readers load the current matcher without locking and keep it alive for as
+/// long as they hold it, so a reload never blocks them and never shows them a
+/// half-built matcher: the replacement is built first and published with a
+/// single atomic store.
+pub struct SharedMatcher<M = MphMatcher> {
+    current: ArcSwap<M>,
+}
+
+impl<M> SharedMatcher<M> {
+    /// Shares a built matcher.
+    pub fn new(matcher: M) -> SharedMatcher<M> {
+        SharedMatcher {
+            current: ArcSwap::from_pointee(matcher),
+        }
+    }
+
+    /// Returns the current matcher. This never blocks, and the matcher stays
+    /// valid for as long as the guard is held, even if it is replaced.
+    pub fn load(&self) -> Guard<Arc<M>> {
+        self.current.load()
+    }
+
+    pub fn reverse_query<V>(&self, query_string: &str) -> bool
+    where
+        M: DomainMatcher<V>,
+    {
+        self.current.load().reverse_query(query_string)
+    }
+
+    /// Publishes `matcher` to all later queries and returns the one it
+    /// replaces. Queries already running keep the old matcher.
+    pub fn store(&self, matcher: M) -> Arc<M> {
+        self.current.swap(Arc::new(matcher))
+    }
+
+    /// Builds a matcher with `build` on the calling thread and publishes it
+    /// if that succeeds. On error the current matcher stays in place.
+    pub fn reload<F, E>(&self, build: F) -> Result<(), E>
+    where
+        F: FnOnce() -> Result<M, E>,
+    {
+        self.store(build()?);
+        Ok(())
+    }
+
+    /// Like `reload`, but builds on a new thread and returns at once.
+    pub fn spawn_reload<F, E>(self: &Arc<Self>, build: F) -> JoinHandle<Result<(), E>>
+    where
+        M: Send + Sync + 'static,
+        F: FnOnce() -> Result<M, E> + Send + 'static,
+        E: Send + 'static,
+    {
+        let shared = Arc::clone(self);
+        thread::spawn(move || shared.reload(build))
+    }
+}
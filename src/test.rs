@@ -1,18 +1,27 @@
 use crate::ac_automaton::ACAutomaton;
 use crate::ac_automaton::HybridMatcher;
-use crate::mph::MphMatcher;
-use crate::{geosite, DomainMatcher, MatchType};
+use crate::group::GroupMatcher;
+#[cfg(feature = "idna")]
+use crate::idn::IdnaMatcher;
+use crate::import::v2fly::{self, SourceError, Sources};
+use crate::import::{abp, clash, dnsmasq, hosts, unbound, SkipReason, SkippedLine, Target};
+use crate::index::FormatError;
+use crate::mph::{MphMatcher, MphMatcherRef, DEFAULT_SEED_LIMIT};
+#[cfg(feature = "arc-swap")]
+use crate::shared::SharedMatcher;
+use crate::{
+    geosite, BuildError, DomainMatcher, MatchType, MatchedRule, MAX_LABEL_LEN, MAX_NAME_LEN,
+};
 use deepsize::DeepSizeOf;
-use std::fs::File;
 
 #[cfg(test)]
 fn test_domain_matcher(matcher: &mut impl DomainMatcher) {
-    matcher.reverse_insert("163.com", MatchType::Domain(true));
-    matcher.reverse_insert("m.126.com", MatchType::Full(true));
-    matcher.reverse_insert("3.com", MatchType::Full(true));
-    matcher.reverse_insert("google.com", MatchType::SubStr(true));
-    matcher.reverse_insert("vgoogle.com", MatchType::SubStr(true));
-    matcher.build();
+    matcher.reverse_insert("163.com", MatchType::Domain(true), ());
+    matcher.reverse_insert("m.126.com", MatchType::Full(true), ());
+    matcher.reverse_insert("3.com", MatchType::Full(true), ());
+    matcher.reverse_insert("google.com", MatchType::SubStr(true), ());
+    matcher.reverse_insert("vgoogle.com", MatchType::SubStr(true), ());
+    matcher.build().unwrap();
     assert_eq!(matcher.reverse_query("126.com"), false);
     assert_eq!(matcher.reverse_query("mm163.com"), false);
     assert_eq!(matcher.reverse_query("m.163.com"), true); // sub domain
@@ -22,9 +31,9 @@ fn test_domain_matcher(matcher: &mut impl DomainMatcher) {
     assert_eq!(matcher.reverse_query("oogle.com"), false);
     assert_eq!(matcher.reverse_query("vvgoogle.com"), true); // substr
     matcher.clear();
-    matcher.reverse_insert("video.google.com", MatchType::Domain(true));
-    matcher.reverse_insert("gle.com", MatchType::Domain(true));
-    matcher.build();
+    matcher.reverse_insert("video.google.com", MatchType::Domain(true), ());
+    matcher.reverse_insert("gle.com", MatchType::Domain(true), ());
+    matcher.build().unwrap();
     assert_eq!(matcher.reverse_query("google.com"), false);
     assert_eq!(matcher.reverse_query("video.google.com.hk"), false); // not sub domain
 }
@@ -48,46 +57,996 @@ fn test_mph_matcher() {
 }
 
 #[cfg(test)]
-fn test_with_geosite(matcher: &mut impl DomainMatcher) {
-    let file = "data/geosite.dat";
-    let mut f = match File::open(&file) {
-        Ok(f) => f,
-        Err(e) => {
-            eprintln!("open dat file {} failed: {}", file, e);
-            return;
+fn test_matched_rule(matcher: &mut impl DomainMatcher<&'static str>) {
+    matcher.reverse_insert("163.com", MatchType::Domain(true), "direct");
+    matcher.reverse_insert("m.126.com", MatchType::Full(true), "proxy");
+    matcher.reverse_insert("google", MatchType::SubStr(true), "proxy");
+    matcher.reverse_insert("163.com", MatchType::Domain(true), "block");
+    matcher.build().unwrap();
+    assert_eq!(
+        matcher.reverse_query_rule("m.163.com"),
+        Some(MatchedRule {
+            pattern: "163.com",
+            match_type: MatchType::Domain(true),
+            index: 0,
+            value: &"direct"
+        })
+    );
+    assert_eq!(
+        matcher.reverse_query_rule("163.com"),
+        Some(MatchedRule {
+            pattern: "163.com",
+            match_type: MatchType::Domain(true),
+            index: 0,
+            value: &"direct"
+        })
+    );
+    assert_eq!(
+        matcher.reverse_query_rule("m.126.com"),
+        Some(MatchedRule {
+            pattern: "m.126.com",
+            match_type: MatchType::Full(true),
+            index: 1,
+            value: &"proxy"
+        })
+    );
+    assert_eq!(
+        matcher.reverse_query_rule("www.google.com.hk"),
+        Some(MatchedRule {
+            pattern: "google",
+            match_type: MatchType::SubStr(true),
+            index: 2,
+            value: &"proxy"
+        })
+    );
+    assert_eq!(matcher.reverse_query_rule("126.com"), None);
+    assert_eq!(matcher.reverse_query_value("a.m.163.com"), Some(&"direct"));
+    assert_eq!(matcher.reverse_query_value("google.cn"), Some(&"proxy"));
+    assert_eq!(matcher.reverse_query_value("126.com"), None);
+}
+
+#[test]
+fn test_matched_rule_all_matchers() {
+    test_matched_rule(&mut ACAutomaton::new(1));
+    test_matched_rule(&mut HybridMatcher::new(1));
+    test_matched_rule(&mut MphMatcher::new(1));
+}
+
+#[cfg(test)]
+fn test_exception_rule(matcher: &mut impl DomainMatcher) {
+    matcher.reverse_insert("example.com", MatchType::Domain(true), ());
+    matcher.reverse_insert("cdn.example.com", MatchType::Domain(false), ());
+    matcher.reverse_insert("img.cdn.example.com", MatchType::Full(true), ());
+    matcher.reverse_insert("ads", MatchType::SubStr(true), ());
+    matcher.reverse_insert("ads.good", MatchType::SubStr(false), ());
+    matcher.reverse_insert(r"^track[0-9]+\.", MatchType::Regex(true), ());
+    matcher.reverse_insert(r"^track0\.", MatchType::Regex(false), ());
+    matcher.build().unwrap();
+    assert!(matcher.reverse_query("example.com"));
+    assert!(matcher.reverse_query("www.example.com"));
+    assert!(!matcher.reverse_query("cdn.example.com"));
+    assert!(!matcher.reverse_query("a.cdn.example.com"));
+    assert!(matcher.reverse_query("img.cdn.example.com"));
+    assert!(!matcher.reverse_query("a.img.cdn.example.com"));
+    assert!(matcher.reverse_query("ads.net"));
+    assert!(!matcher.reverse_query("ads.good.net"));
+    // Domain rules are more specific than SubStr rules.
+    assert!(!matcher.reverse_query("ads.cdn.example.com"));
+    assert!(matcher.reverse_query("track1.net"));
+    assert!(!matcher.reverse_query("track0.net"));
+    assert_eq!(
+        matcher
+            .reverse_query_rule("www.example.com")
+            .map(|rule| rule.index),
+        Some(0)
+    );
+    assert_eq!(matcher.reverse_query_rule("cdn.example.com"), None);
+}
+
+#[test]
+fn test_exception_rule_all_matchers() {
+    test_exception_rule(&mut ACAutomaton::new(1));
+    test_exception_rule(&mut HybridMatcher::new(1));
+    test_exception_rule(&mut MphMatcher::new(1));
+}
+
+#[cfg(test)]
+fn test_full_over_domain<M: DomainMatcher>(new: impl Fn() -> M) {
+    for full_first in [false, true] {
+        for after_build in [false, true] {
+            let mut matcher = new();
+            matcher.reverse_insert("b.com", MatchType::Full(true), ());
+            if after_build {
+                matcher.build().unwrap();
+            }
+            let rules = [MatchType::Domain(false), MatchType::Full(true)];
+            for match_type in if full_first {
+                [rules[1], rules[0]]
+            } else {
+                rules
+            } {
+                matcher.reverse_insert("a.com", match_type, ());
+            }
+            matcher.build().unwrap();
+            assert!(matcher.reverse_query("a.com"));
+            assert!(!matcher.reverse_query("x.a.com"));
+            // the Domain rule gets the name back
+            assert!(matcher.remove("a.com", MatchType::Full(true)));
+            matcher.build().unwrap();
+            assert!(!matcher.reverse_query("a.com"));
+            assert!(!matcher.reverse_query("x.a.com"));
+        }
+    }
+}
+
+#[test]
+fn test_full_over_domain_all_matchers() {
+    test_full_over_domain(|| ACAutomaton::new(1));
+    test_full_over_domain(|| HybridMatcher::new(1));
+    test_full_over_domain(|| MphMatcher::new(1));
+}
+
+#[cfg(test)]
+fn test_query_all(matcher: &mut impl DomainMatcher) {
+    matcher.reverse_insert("com", MatchType::Domain(true), ());
+    matcher.reverse_insert("google.com", MatchType::Domain(true), ());
+    matcher.reverse_insert("mail.google.com", MatchType::Full(false), ());
+    matcher.reverse_insert("google", MatchType::SubStr(true), ());
+    matcher.reverse_insert("mail", MatchType::SubStr(false), ());
+    matcher.reverse_insert(r"google\.com$", MatchType::Regex(true), ());
+    matcher.build().unwrap();
+    let all = |query| {
+        matcher
+            .reverse_query_all(query)
+            .map(|rule| rule.index)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(all("www.google.com"), vec![1, 0, 3, 5]);
+    assert_eq!(all("mail.google.com"), vec![2, 1, 0, 4, 3, 5]);
+    assert_eq!(all("google.com"), vec![1, 0, 3, 5]);
+    assert_eq!(all("example.org"), Vec::<usize>::new());
+    assert_eq!(
+        matcher
+            .reverse_query_rule("www.google.com")
+            .map(|rule| rule.index),
+        Some(1)
+    );
+    assert!(!matcher.reverse_query("mail.google.com"));
+}
+
+#[test]
+fn test_query_all_matchers() {
+    test_query_all(&mut ACAutomaton::new(1));
+    test_query_all(&mut HybridMatcher::new(1));
+    test_query_all(&mut MphMatcher::new(1));
+}
+
+#[cfg(test)]
+fn test_case_folding(matcher: &mut impl DomainMatcher, case_sensitive: bool) {
+    matcher.reverse_insert("Google.com", MatchType::Domain(true), ());
+    matcher.reverse_insert("m.126.com", MatchType::Full(true), ());
+    matcher.reverse_insert("AdService", MatchType::SubStr(true), ());
+    matcher.reverse_insert(r"^track\.", MatchType::Regex(true), ());
+    matcher.build().unwrap();
+    assert!(matcher.reverse_query("Google.com"));
+    assert!(matcher.reverse_query("www.Google.com"));
+    assert!(matcher.reverse_query("m.126.com"));
+    assert!(matcher.reverse_query("AdService.net"));
+    assert!(matcher.reverse_query("track.net"));
+    for query in [
+        "google.com",
+        "WWW.GOOGLE.COM",
+        "M.126.Com",
+        "adservice.net",
+        "TRACK.net",
+    ] {
+        assert_eq!(matcher.reverse_query(query), !case_sensitive, "{}", query);
+    }
+}
+
+#[test]
+fn test_case_folding_all_matchers() {
+    test_case_folding(&mut ACAutomaton::new(1), false);
+    test_case_folding(&mut HybridMatcher::new(1), false);
+    test_case_folding(&mut MphMatcher::new(1), false);
+    test_case_folding(&mut ACAutomaton::new_case_sensitive(1), true);
+    test_case_folding(&mut HybridMatcher::new_case_sensitive(1), true);
+    test_case_folding(&mut MphMatcher::new_case_sensitive(1), true);
+}
+
+#[test]
+fn test_case_folding_write_read() {
+    for case_sensitive in [false, true] {
+        let mut mph_matcher = if case_sensitive {
+            MphMatcher::new_case_sensitive(1)
+        } else {
+            MphMatcher::new(1)
+        };
+        mph_matcher.reverse_insert("Google.com", MatchType::Domain(true), ());
+        mph_matcher.reverse_insert("AdService", MatchType::SubStr(true), ());
+        mph_matcher.build().unwrap();
+        let mut bytes = Vec::new();
+        mph_matcher.write_to(&mut bytes).unwrap();
+        let loaded = MphMatcher::read_from(&mut bytes.as_slice()).unwrap();
+        let view = MphMatcherRef::new(&bytes).unwrap();
+        assert_eq!(loaded.is_case_sensitive(), case_sensitive);
+        for query in ["www.google.COM", "Google.com", "ADSERVICE.net", "AdService"] {
+            assert_eq!(
+                loaded.reverse_query(query),
+                mph_matcher.reverse_query(query)
+            );
+            assert_eq!(view.reverse_query(query), mph_matcher.reverse_query(query));
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_full_byte_range(matcher: &mut impl DomainMatcher) {
+    matcher.reverse_insert("a.com", MatchType::SubStr(true), ());
+    matcher.reverse_insert("a/b", MatchType::SubStr(true), ());
+    matcher.reverse_insert("\u{fc}ber", MatchType::SubStr(true), ());
+    matcher.reverse_insert("b\u{fc}cher.de", MatchType::Domain(true), ());
+    matcher.build().unwrap();
+    assert!(!matcher.reverse_query("\u{fc}.com"));
+    assert!(matcher.reverse_query("xa.com"));
+    assert!(matcher.reverse_query("x/a/b"));
+    assert!(!matcher.reverse_query("x/a@b"));
+    assert!(!matcher.reverse_query("x/a b"));
+    assert!(matcher.reverse_query("\u{fc}ber.net"));
+    assert!(!matcher.reverse_query("aber.net"));
+    assert!(!matcher.reverse_query("\u{dc}ber.net"));
+    assert!(matcher.reverse_query("www.b\u{fc}cher.de"));
+    assert!(!matcher.reverse_query("www.bacher.de"));
+}
+
+#[test]
+fn test_seed_limit() {
+    let mut matcher = MphMatcher::new(1);
+    for i in 0..1000 {
+        matcher.reverse_insert(&format!("site{}.com", i), MatchType::Domain(true), ());
+    }
+    matcher.set_seed_limit(0);
+    assert_eq!(matcher.build(), Err(BuildError::SeedLimitExceeded(0)));
+    assert!(!matcher.reverse_query("example.com"));
+
+    matcher.set_seed_limit(DEFAULT_SEED_LIMIT);
+    assert_eq!(matcher.build(), Ok(()));
+    assert!(matcher.reverse_query("www.site999.com"));
+    assert!(!matcher.reverse_query("site1000.com"));
+}
+
+#[cfg(test)]
+fn test_invalid_regex(matcher: &mut impl DomainMatcher) {
+    matcher.reverse_insert(r"^ad[0-9]+\.", MatchType::Regex(true), ());
+    matcher.reverse_insert("(", MatchType::Regex(true), ());
+    matcher.reverse_insert(r"\w{5000}", MatchType::Regex(true), ());
+    matcher.reverse_insert(r"^track\.", MatchType::Regex(true), ());
+    assert_eq!(
+        matcher.build(),
+        Err(BuildError::InvalidRegex("(".to_string()))
+    );
+    assert!(matcher.reverse_query("AD1.net"));
+    assert!(matcher.reverse_query("track.net"));
+    assert!(matcher.remove("(", MatchType::Regex(true)));
+    assert_eq!(
+        matcher.build(),
+        Err(BuildError::InvalidRegex(r"\w{5000}".to_string()))
+    );
+    assert!(matcher.remove(r"\w{5000}", MatchType::Regex(true)));
+    assert_eq!(matcher.build(), Ok(()));
+    // inserted after build, reported by the next one
+    matcher.reverse_insert("[", MatchType::Regex(true), ());
+    assert!(matcher.reverse_query("ad2.net"));
+    assert_eq!(
+        matcher.build(),
+        Err(BuildError::InvalidRegex("[".to_string()))
+    );
+}
+
+#[test]
+fn test_invalid_regex_all_matchers() {
+    test_invalid_regex(&mut ACAutomaton::new(1));
+    test_invalid_regex(&mut HybridMatcher::new(1));
+    test_invalid_regex(&mut MphMatcher::new(1));
+}
+
+#[test]
+fn test_full_byte_range_all_matchers() {
+    test_full_byte_range(&mut ACAutomaton::new(1));
+    test_full_byte_range(&mut HybridMatcher::new(1));
+    test_full_byte_range(&mut MphMatcher::new(1));
+}
+
+#[cfg(test)]
+fn test_malformed_query(matcher: &mut impl DomainMatcher) {
+    matcher.reverse_insert("example.com", MatchType::Domain(true), ());
+    matcher.reverse_insert("a.org", MatchType::Full(true), ());
+    matcher.reverse_insert("ads", MatchType::SubStr(true), ());
+    matcher.reverse_insert(r"^track\.", MatchType::Regex(true), ());
+    matcher.build().unwrap();
+    assert!(matcher.reverse_query("example.com."));
+    assert!(matcher.reverse_query("www.example.com."));
+    assert!(matcher.reverse_query("a.org."));
+    assert!(matcher.reverse_query("ads."));
+    assert!(matcher.reverse_query("track.net."));
+    assert_eq!(matcher.reverse_query_all("example.com.").count(), 1);
+    let long_label = format!("{}.example.com", "a".repeat(MAX_LABEL_LEN));
+    assert!(matcher.reverse_query(&long_label));
+    let long_name = format!("{}example.com", "a.".repeat(MAX_NAME_LEN / 2 - 5));
+    assert_eq!(long_name.len(), MAX_NAME_LEN);
+    assert!(matcher.reverse_query(&long_name));
+    let too_long_label = format!("{}.example.com", "a".repeat(MAX_LABEL_LEN + 1));
+    let too_long_name = format!("a{}", long_name);
+    for query in [
+        "",
+        ".",
+        "..",
+        ".example.com",
+        "www..example.com",
+        "example.com..",
+        "ads..",
+        too_long_label.as_str(),
+        too_long_name.as_str(),
+    ] {
+        assert!(!matcher.reverse_query(query));
+        assert_eq!(matcher.reverse_query_rule(query), None);
+        assert_eq!(matcher.reverse_query_all(query).count(), 0);
+    }
+}
+
+#[test]
+fn test_malformed_query_all_matchers() {
+    test_malformed_query(&mut ACAutomaton::new(1));
+    test_malformed_query(&mut HybridMatcher::new(1));
+    test_malformed_query(&mut MphMatcher::new(1));
+    let mut mph_matcher = MphMatcher::new(1);
+    mph_matcher.reverse_insert("example.com", MatchType::Domain(true), ());
+    mph_matcher.build().unwrap();
+    let mut bytes = Vec::new();
+    mph_matcher.write_to(&mut bytes).unwrap();
+    let view = MphMatcherRef::new(&bytes).unwrap();
+    assert!(view.reverse_query("example.com."));
+    assert!(!view.reverse_query(""));
+    assert!(!view.reverse_query(".example.com"));
+    let group_matcher = GroupMatcher::new(vec![(
+        "test",
+        vec![("example.com", MatchType::Domain(true))],
+    )])
+    .unwrap();
+    assert_eq!(
+        group_matcher.reverse_query_tags("www.example.com."),
+        vec!["test"]
+    );
+    assert!(group_matcher.reverse_query("").is_empty());
+}
+
+#[cfg(test)]
+fn test_incremental_update<M: DomainMatcher<usize>>(new: impl Fn() -> M) {
+    let rules = [
+        ("example.com", MatchType::Domain(true)),
+        ("cdn.example.com", MatchType::Domain(false)),
+        ("a.org", MatchType::Full(true)),
+        ("ads", MatchType::SubStr(true)),
+        ("sad", MatchType::SubStr(true)),
+        ("ample.c", MatchType::SubStr(true)),
+        (r"^track[0-9]+\.", MatchType::Regex(true)),
+    ];
+    let later = [
+        ("ple.co", MatchType::SubStr(true)),
+        ("xample", MatchType::SubStr(false)),
+        ("m.example.com", MatchType::Full(true)),
+        ("a.org", MatchType::Domain(true)),
+        ("ad", MatchType::SubStr(true)),
+        ("s.c", MatchType::SubStr(true)),
+        (r"^track1", MatchType::Regex(false)),
+    ];
+    let removed = [
+        ("ads", MatchType::SubStr(true)),
+        ("Example.com", MatchType::Domain(true)),
+        ("a.org", MatchType::Full(true)),
+        (r"^track[0-9]+\.", MatchType::Regex(true)),
+    ];
+    let mut matcher = new();
+    for (pattern, match_type) in rules.iter() {
+        matcher.reverse_insert(pattern, *match_type, 0);
+    }
+    matcher.build().unwrap();
+    for (pattern, match_type) in later.iter() {
+        matcher.reverse_insert(pattern, *match_type, 0);
+    }
+    for (pattern, match_type) in removed.iter() {
+        assert!(matcher.remove(pattern, *match_type));
+    }
+    assert!(!matcher.remove("ads", MatchType::SubStr(true)));
+    assert!(!matcher.remove("sad", MatchType::SubStr(false)));
+
+    let mut fresh = new();
+    for (pattern, match_type) in rules.iter().chain(later.iter()) {
+        if !removed
+            .iter()
+            .any(|(p, t)| p.eq_ignore_ascii_case(pattern) && t == match_type)
+        {
+            fresh.reverse_insert(pattern, *match_type, 0);
+        }
+    }
+    fresh.build().unwrap();
+    let fragments = [
+        "a", "d", "s", "ad", "ex", "ample", ".com", "ple.co", "track1", ".org",
+    ];
+    for a in fragments.iter() {
+        for b in fragments.iter() {
+            for c in [
+                "",
+                ".com",
+                ".example.com",
+                "m.example.com",
+                "cdn.example.com",
+            ] {
+                let query = format!("{}{}{}", a, b, c);
+                let all = |m: &M| {
+                    m.reverse_query_all(&query)
+                        .map(|rule| (rule.pattern.to_string(), rule.match_type))
+                        .collect::<Vec<_>>()
+                };
+                assert_eq!(all(&matcher), all(&fresh), "{}", query);
+                assert_eq!(
+                    matcher.reverse_query(&query),
+                    fresh.reverse_query(&query),
+                    "{}",
+                    query
+                );
+            }
+        }
+    }
+    assert!(!matcher.reverse_query("www.example.com"));
+    assert!(matcher.reverse_query("m.example.com"));
+    assert!(matcher.reverse_query("www.a.org"));
+    assert!(!matcher.reverse_query("track2.net"));
+    matcher.build().unwrap();
+    assert!(matcher.reverse_query("m.example.com"));
+    assert!(!matcher.reverse_query("track2.net"));
+    assert_eq!(
+        matcher
+            .reverse_query_rule("www.a.org")
+            .map(|rule| rule.index),
+        Some(10)
+    );
+}
+
+#[test]
+fn test_incremental_update_all_matchers() {
+    test_incremental_update(|| ACAutomaton::new(1));
+    test_incremental_update(|| HybridMatcher::new(1));
+    test_incremental_update(|| MphMatcher::new(1));
+}
+
+#[cfg(test)]
+fn test_remove_shadowed(matcher: &mut impl DomainMatcher<&'static str>) {
+    matcher.reverse_insert("a.com", MatchType::Domain(true), "domain");
+    matcher.reverse_insert("a.com", MatchType::Full(false), "full");
+    matcher.reverse_insert("ads", MatchType::SubStr(true), "first");
+    matcher.reverse_insert("ads", MatchType::SubStr(false), "second");
+    matcher.build().unwrap();
+    assert_eq!(matcher.reverse_query_value("a.com"), None);
+    assert_eq!(matcher.reverse_query_value("www.a.com"), Some(&"domain"));
+    assert_eq!(matcher.reverse_query_value("ads.net"), Some(&"first"));
+    assert!(matcher.remove("a.com", MatchType::Domain(true)));
+    assert!(matcher.remove("ads", MatchType::SubStr(true)));
+    assert!(!matcher.reverse_query("a.com"));
+    assert_eq!(
+        matcher
+            .reverse_query_all("a.com")
+            .next()
+            .map(|rule| rule.value),
+        Some(&"full")
+    );
+    assert!(!matcher.reverse_query("www.a.com"));
+    assert!(!matcher.reverse_query("ads.net"));
+    matcher.reverse_insert("a.com", MatchType::Domain(true), "again");
+    assert_eq!(matcher.reverse_query_value("www.a.com"), Some(&"again"));
+    assert!(!matcher.reverse_query("a.com"));
+    assert!(matcher.remove("a.com", MatchType::Full(false)));
+    assert_eq!(matcher.reverse_query_value("a.com"), Some(&"again"));
+}
+
+#[test]
+fn test_remove_shadowed_all_matchers() {
+    test_remove_shadowed(&mut ACAutomaton::new(1));
+    test_remove_shadowed(&mut HybridMatcher::new(1));
+    test_remove_shadowed(&mut MphMatcher::new(1));
+}
+
+#[test]
+fn test_colliding_patterns_all_matchers() {
+    // Rules of different types on the same folded pattern share trie nodes
+    // and hash keys, yet every matcher must pick the same rules.
+    let mut seed = 54321u32;
+    let mut random = |n: usize| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        (seed >> 16) as usize % n
+    };
+    let names = ["b", "Ab", "ab", "a.b", "b.a", "c.b"];
+    let types = [MatchType::SubStr, MatchType::Domain, MatchType::Full];
+    let rules: Vec<(&str, MatchType)> = (0..40)
+        .map(|_| {
+            (
+                names[random(names.len())],
+                types[random(types.len())](random(3) != 0),
+            )
+        })
+        .collect();
+    let queries: Vec<String> = (0..300)
+        .map(|_| {
+            (0..1 + random(6))
+                .map(|_| ["a", "b", "c", "."][random(4)])
+                .collect()
+        })
+        .chain(["ab.a.c".to_string(), "xab.b".to_string()])
+        .collect();
+    let mut ac = ACAutomaton::new(1);
+    let mut hybrid = HybridMatcher::new(1);
+    let mut mph = MphMatcher::new(1);
+    for (pattern, match_type) in rules[..30].iter() {
+        ac.reverse_insert(pattern, *match_type, ());
+        hybrid.reverse_insert(pattern, *match_type, ());
+        mph.reverse_insert(pattern, *match_type, ());
+    }
+    ac.build().unwrap();
+    hybrid.build().unwrap();
+    mph.build().unwrap();
+    let check = |ac: &ACAutomaton, hybrid: &HybridMatcher, mph: &MphMatcher| {
+        for query in queries.iter() {
+            let expected = mph.reverse_query_rule(query).map(|r| r.index);
+            assert_eq!(ac.reverse_query_rule(query).map(|r| r.index), expected);
+            assert_eq!(hybrid.reverse_query_rule(query).map(|r| r.index), expected);
+            let expected: Vec<usize> = mph.reverse_query_all(query).map(|r| r.index).collect();
+            let all: Vec<usize> = ac.reverse_query_all(query).map(|r| r.index).collect();
+            assert_eq!(all, expected, "{}", query);
+            let all: Vec<usize> = hybrid.reverse_query_all(query).map(|r| r.index).collect();
+            assert_eq!(all, expected, "{}", query);
         }
     };
-    let site_group_list: geosite::SiteGroupList = match protobuf::Message::parse_from_reader(&mut f)
-    {
+    check(&ac, &hybrid, &mph);
+    for (pattern, match_type) in rules[30..].iter() {
+        ac.reverse_insert(pattern, *match_type, ());
+        hybrid.reverse_insert(pattern, *match_type, ());
+        mph.reverse_insert(pattern, *match_type, ());
+    }
+    check(&ac, &hybrid, &mph);
+    for (pattern, match_type) in rules[..10].iter() {
+        ac.remove(pattern, *match_type);
+        hybrid.remove(pattern, *match_type);
+        mph.remove(pattern, *match_type);
+    }
+    check(&ac, &hybrid, &mph);
+
+    let mut ac = ACAutomaton::new(1);
+    ac.reverse_insert("b", MatchType::SubStr(true), ());
+    ac.reverse_insert("b", MatchType::Domain(true), ());
+    ac.build().unwrap();
+    assert_eq!(ac.reverse_query_rule("ab.a.c").map(|r| r.index), Some(0));
+    ac.reverse_insert("Ab", MatchType::SubStr(false), ());
+    ac.reverse_insert("Ab", MatchType::Full(true), ());
+    assert_eq!(ac.reverse_query_rule("ab").map(|r| r.index), Some(3));
+    assert!(!ac.reverse_query("xab.c"));
+    let all: Vec<usize> = ac.reverse_query_all("ab").map(|r| r.index).collect();
+    assert_eq!(all, vec![3, 2, 0]);
+}
+
+#[test]
+fn test_ac_automaton_incremental_links() {
+    // Patterns and queries over a small alphabet share many suffixes, which
+    // stresses relinking.
+    let mut seed = 12345u32;
+    let mut random_string = |max_len: u32| {
+        seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+        let len = 1 + (seed >> 16) % max_len;
+        (0..len)
+            .map(|_| {
+                seed = seed.wrapping_mul(1103515245).wrapping_add(12345);
+                ['a', 'b', 'c', '\u{fc}'][(seed >> 16) as usize % 4]
+            })
+            .collect::<String>()
+    };
+    let patterns: Vec<String> = (0..60).map(|_| random_string(5)).collect();
+    let queries: Vec<String> = (0..300).map(|_| random_string(12)).collect();
+    let mut matcher = ACAutomaton::new(1);
+    for pattern in patterns[..20].iter() {
+        matcher.reverse_insert(pattern, MatchType::SubStr(true), ());
+    }
+    matcher.build().unwrap();
+    for pattern in patterns[20..].iter() {
+        matcher.reverse_insert(pattern, MatchType::SubStr(true), ());
+    }
+    let mut fresh = ACAutomaton::new(1);
+    for pattern in patterns.iter() {
+        fresh.reverse_insert(pattern, MatchType::SubStr(true), ());
+    }
+    fresh.build().unwrap();
+    for query in queries.iter() {
+        let all = |m: &ACAutomaton| {
+            m.reverse_query_all(query)
+                .map(|rule| rule.index)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(all(&matcher), all(&fresh), "{}", query);
+    }
+}
+
+#[test]
+fn test_empty_write_read() {
+    let mut mph_matcher = MphMatcher::new(1);
+    mph_matcher.reverse_insert("a.org", MatchType::Full(true), ());
+    let err = mph_matcher.write_to(&mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    let mut empty = MphMatcher::<()>::new(1);
+    assert!(empty.write_to(&mut Vec::new()).is_err());
+    empty.build().unwrap();
+    let mut bytes = Vec::new();
+    empty.write_to(&mut bytes).unwrap();
+    let view = MphMatcherRef::new(&bytes).unwrap();
+    let loaded = MphMatcher::read_from(&mut bytes.as_slice()).unwrap();
+    assert!(!view.reverse_query("a.org"));
+    assert!(!loaded.reverse_query("a.org"));
+    let mut again = Vec::new();
+    loaded.write_to(&mut again).unwrap();
+    assert_eq!(again, bytes);
+}
+
+#[test]
+fn test_remove_write_read() {
+    let mut mph_matcher = MphMatcher::new(1);
+    mph_matcher.reverse_insert("example.com", MatchType::Domain(true), ());
+    mph_matcher.reverse_insert("a.org", MatchType::Full(true), ());
+    mph_matcher.reverse_insert("ads", MatchType::SubStr(true), ());
+    mph_matcher.build().unwrap();
+    mph_matcher.remove("example.com", MatchType::Domain(true));
+    mph_matcher.remove("ads", MatchType::SubStr(true));
+    let mut bytes = Vec::new();
+    mph_matcher.write_to(&mut bytes).unwrap();
+    let view = MphMatcherRef::new(&bytes).unwrap();
+    let mut loaded = MphMatcher::read_from(&mut bytes.as_slice()).unwrap();
+    for query in ["example.com", "www.example.com", "a.org", "ads.net"] {
+        assert_eq!(view.reverse_query(query), mph_matcher.reverse_query(query));
+        assert_eq!(
+            loaded.reverse_query(query),
+            mph_matcher.reverse_query(query)
+        );
+    }
+    assert!(view.reverse_query("a.org"));
+    // A removed rule stays removed once loaded.
+    loaded.reverse_insert("a.org", MatchType::Domain(true), ());
+    assert!(loaded.remove("a.org", MatchType::Full(true)));
+    assert!(loaded.reverse_query("a.org"));
+    assert_eq!(
+        loaded.reverse_query_rule("a.org").map(|rule| rule.index),
+        Some(3)
+    );
+    assert!(!loaded.remove("example.com", MatchType::Domain(true)));
+
+    // Keys inserted after build() are only written once merged.
+    mph_matcher.reverse_insert("b.com", MatchType::Full(true), ());
+    mph_matcher.reverse_insert("kw", MatchType::SubStr(true), ());
+    let err = mph_matcher.write_to(&mut Vec::new()).unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    mph_matcher.build().unwrap();
+    let mut bytes = Vec::new();
+    mph_matcher.write_to(&mut bytes).unwrap();
+    let loaded = MphMatcher::read_from(&mut bytes.as_slice()).unwrap();
+    assert!(loaded.reverse_query("b.com"));
+    assert!(loaded.reverse_query("kw.net"));
+}
+
+#[test]
+fn test_batch_query() {
+    let mut matcher = MphMatcher::new(1);
+    matcher.reverse_insert("example.com", MatchType::Domain(true), ());
+    matcher.reverse_insert("cdn.example.com", MatchType::Domain(false), ());
+    matcher.reverse_insert("img.cdn.example.com", MatchType::Full(true), ());
+    matcher.reverse_insert("163.com", MatchType::Full(true), ());
+    matcher.reverse_insert("google", MatchType::SubStr(true), ());
+    matcher.reverse_insert("^ad[0-9]+\\.", MatchType::Regex(true), ());
+    matcher.build().unwrap();
+    // kept aside until the next build
+    matcher.reverse_insert("late.org", MatchType::Domain(true), ());
+    let queries = [
+        "example.com",
+        "www.Example.com.",
+        "cdn.example.com",
+        "a.cdn.example.com",
+        "img.cdn.example.com",
+        "163.com",
+        "m.163.com",
+        "www.google.cn",
+        "ad12.net",
+        "x.late.org",
+        "",
+        "a..com",
+        ".example.com",
+        "unknown.net",
+    ];
+    let expected: Vec<_> = queries
+        .iter()
+        .map(|query| {
+            let rule: Option<MatchedRule> = matcher.reverse_query_rule(query);
+            rule.map(|rule| rule.index)
+        })
+        .collect();
+    // more queries than one group of lanes
+    let queries: Vec<&str> = queries
+        .iter()
+        .cycle()
+        .take(queries.len() * 5)
+        .copied()
+        .collect();
+    let expected: Vec<_> = expected
+        .iter()
+        .cycle()
+        .take(queries.len())
+        .copied()
+        .collect();
+    assert_eq!(matcher.reverse_query_index_batch(&queries), expected);
+    assert_eq!(
+        matcher.reverse_query_batch(&queries),
+        expected.iter().map(|id| id.is_some()).collect::<Vec<_>>()
+    );
+    assert!(matcher.reverse_query_batch(&[]).is_empty());
+    #[cfg(feature = "rayon")]
+    assert_eq!(matcher.par_reverse_query_index_batch(&queries), expected);
+}
+
+#[test]
+fn test_query_without_build() {
+    let mut matcher = MphMatcher::new(1);
+    assert!(!matcher.reverse_query("example.com"));
+    matcher.reverse_insert("example.com", MatchType::Domain(true), ());
+    assert!(!matcher.reverse_query("www.example.com"));
+    assert_eq!(matcher.reverse_query_batch(&["www.example.com"]), [false]);
+    matcher.build().unwrap();
+    assert!(matcher.reverse_query("www.example.com"));
+    matcher.clear();
+    assert!(!matcher.reverse_query("www.example.com"));
+    assert_eq!(
+        matcher.reverse_query_batch(&["www.example.com", ""]),
+        [false, false]
+    );
+}
+
+#[test]
+fn test_batch_query_with_geosite() {
+    let file = "data/geosite.dat";
+    if !std::path::Path::new(file).exists() {
+        return;
+    }
+    let list = geosite::load_site_group_list(file).unwrap();
+    let mut matcher = MphMatcher::new(1);
+    geosite::insert_group(&mut matcher, geosite::find_group(&list, "cn").unwrap());
+    matcher.build().unwrap();
+    let queries: Vec<String> = list
+        .site_group
+        .iter()
+        .flat_map(|group| group.domain.iter())
+        .filter(|domain| domain.field_type != geosite::Domain_Type::Regex)
+        .map(|domain| format!("www.{}", domain.get_value()))
+        .collect();
+    let queries: Vec<&str> = queries.iter().map(|query| query.as_str()).collect();
+    let expected: Vec<bool> = queries
+        .iter()
+        .map(|query| matcher.reverse_query(query))
+        .collect();
+    assert_eq!(matcher.reverse_query_batch(&queries), expected);
+    #[cfg(feature = "rayon")]
+    assert_eq!(matcher.par_reverse_query_batch(&queries), expected);
+}
+
+#[test]
+fn test_send_sync() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<ACAutomaton>();
+    assert_send_sync::<HybridMatcher<String>>();
+    assert_send_sync::<MphMatcher<String>>();
+    assert_send_sync::<MphMatcherRef>();
+    assert_send_sync::<GroupMatcher>();
+}
+
+#[cfg(feature = "arc-swap")]
+#[test]
+fn test_shared_matcher() {
+    use std::sync::Arc;
+    use std::thread;
+
+    // Generation `n` holds `gen{n}.com` and `both{n}.com`, so a reader that
+    // sees one without the other saw a half-built matcher.
+    let generation = |n: usize| -> Result<MphMatcher, crate::BuildError> {
+        let mut matcher = MphMatcher::new(1);
+        matcher.reverse_insert(&format!("gen{}.com", n), MatchType::Domain(true), ());
+        matcher.reverse_insert(&format!("both{}.com", n), MatchType::Full(true), ());
+        matcher.build()?;
+        Ok(matcher)
+    };
+    let shared = Arc::new(SharedMatcher::new(generation(0).unwrap()));
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let shared = Arc::clone(&shared);
+            thread::spawn(move || {
+                let mut last = 0;
+                while last < 20 {
+                    let matcher = shared.load();
+                    let n = (last..=20)
+                        .find(|n| matcher.reverse_query(&format!("www.gen{}.com", n)))
+                        .unwrap();
+                    assert!(matcher.reverse_query(&format!("both{}.com", n)));
+                    last = n;
+                }
+            })
+        })
+        .collect();
+    for n in 1..20 {
+        shared.reload(|| generation(n)).unwrap();
+    }
+    shared
+        .spawn_reload(move || generation(20))
+        .join()
+        .unwrap()
+        .unwrap();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    assert!(shared.reverse_query("gen20.com"));
+    assert!(!shared.reverse_query("gen19.com"));
+    let failed: Result<(), &str> = shared.reload(|| Err("bad rules"));
+    assert_eq!(failed, Err("bad rules"));
+    assert!(shared.reverse_query("gen20.com"));
+}
+
+#[cfg(feature = "ffi")]
+#[test]
+fn test_ffi() {
+    use crate::ffi::*;
+    use std::ffi::{CStr, CString};
+    use std::ptr;
+
+    let pattern = CString::new("example.com").unwrap();
+    let query = CString::new("www.example.com").unwrap();
+    let invalid = [0xffu8, 0];
+    unsafe {
+        let matcher = dm_matcher_new();
+        assert_eq!(
+            dm_matcher_insert(matcher, pattern.as_ptr(), DmMatchType::Domain as u32, false),
+            DmStatus::Ok
+        );
+        assert_eq!(
+            dm_matcher_insert(
+                matcher,
+                invalid.as_ptr() as *const _,
+                DmMatchType::Full as u32,
+                false
+            ),
+            DmStatus::InvalidUtf8
+        );
+        assert_eq!(
+            dm_matcher_insert(matcher, ptr::null(), DmMatchType::Full as u32, false),
+            DmStatus::NullPointer
+        );
+        assert_eq!(
+            dm_matcher_insert(matcher, pattern.as_ptr(), 4, false),
+            DmStatus::InvalidMatchType
+        );
+        assert_eq!(dm_matcher_build(matcher), DmStatus::Ok);
+        let mut matched = false;
+        assert_eq!(
+            dm_matcher_query(matcher, query.as_ptr(), &mut matched),
+            DmStatus::Ok
+        );
+        assert!(matched);
+        let mut removed = false;
+        assert_eq!(
+            dm_matcher_remove(
+                matcher,
+                pattern.as_ptr(),
+                DmMatchType::Domain as u32,
+                false,
+                &mut removed
+            ),
+            DmStatus::Ok
+        );
+        assert!(removed);
+        dm_matcher_query(matcher, query.as_ptr(), &mut matched);
+        assert!(!matched);
+        assert_eq!(
+            dm_matcher_query(matcher, query.as_ptr(), ptr::null_mut()),
+            DmStatus::NullPointer
+        );
+        dm_matcher_free(matcher);
+        dm_matcher_free(ptr::null_mut());
+
+        let path = CString::new("data/geosite.dat").unwrap();
+        let selector = CString::new("cn").unwrap();
+        let unknown = CString::new("no-such-tag").unwrap();
+        let mut loaded = ptr::null_mut();
+        assert_eq!(
+            dm_matcher_load_geosite(path.as_ptr(), unknown.as_ptr(), &mut loaded),
+            DmStatus::UnknownTag
+        );
+        assert!(loaded.is_null());
+        assert_eq!(
+            CStr::from_ptr(dm_status_str(DmStatus::UnknownTag as u32)).to_str(),
+            Ok("no site group with this tag")
+        );
+        assert_eq!(
+            CStr::from_ptr(dm_status_str(u32::MAX)).to_str(),
+            Ok("unknown status")
+        );
+        assert_eq!(
+            dm_matcher_load_geosite(path.as_ptr(), selector.as_ptr(), &mut loaded),
+            DmStatus::Ok
+        );
+        let baidu = CString::new("www.baidu.com").unwrap();
+        dm_matcher_query(loaded, baidu.as_ptr(), &mut matched);
+        assert!(matched);
+        dm_matcher_free(loaded);
+    }
+}
+
+#[cfg(feature = "idna")]
+fn test_idna(matcher: impl DomainMatcher) {
+    let mut matcher = IdnaMatcher::new(matcher);
+    matcher.reverse_insert("b\u{fc}cher.de", MatchType::Domain(true), ());
+    matcher.reverse_insert("\u{4e2d}\u{56fd}.cn", MatchType::Full(true), ());
+    matcher.reverse_insert("xn--mnchen-3ya.de", MatchType::Domain(true), ());
+    matcher.reverse_insert("ads", MatchType::SubStr(true), ());
+    matcher.reverse_insert("\u{65e5}\u{672c}", MatchType::SubStr(true), ());
+    matcher.build().unwrap();
+    assert!(matcher.reverse_query("b\u{fc}cher.de"));
+    assert!(matcher.reverse_query("www.xn--bcher-kva.de"));
+    assert!(matcher.reverse_query("WWW.B\u{dc}CHER.DE"));
+    assert!(matcher.reverse_query("xn--fiqs8s.cn"));
+    assert!(matcher.reverse_query("\u{4e2d}\u{56fd}.cn"));
+    assert!(!matcher.reverse_query("www.\u{4e2d}\u{56fd}.cn"));
+    assert!(matcher.reverse_query("m\u{fc}nchen.de"));
+    assert!(matcher.reverse_query("ads.m\u{fc}nchen.com"));
+    assert!(!matcher.reverse_query("bucher.de"));
+    assert!(matcher.reverse_query("\u{65e5}\u{672c}.jp"));
+    assert!(matcher.reverse_query("www.\u{65e5}\u{672c}.com"));
+    assert!(matcher.reverse_query("xn--wgv71a.jp"));
+    assert!(!matcher.reverse_query("\u{65e5}.jp"));
+    assert!(matcher.remove("\u{65e5}\u{672c}", MatchType::SubStr(true)));
+    assert!(!matcher.reverse_query("\u{65e5}\u{672c}.jp"));
+    assert_eq!(
+        matcher
+            .reverse_query_rule("b\u{fc}cher.de")
+            .map(|rule| rule.pattern),
+        Some("xn--bcher-kva.de")
+    );
+}
+
+#[test]
+#[cfg(feature = "idna")]
+fn test_idna_all_matchers() {
+    test_idna(ACAutomaton::new(1));
+    test_idna(HybridMatcher::new(1));
+    test_idna(MphMatcher::new(1));
+}
+
+#[cfg(test)]
+fn test_with_geosite(matcher: &mut impl DomainMatcher) {
+    let file = "data/geosite.dat";
+    let site_group_list = match geosite::load_site_group_list(file) {
         Ok(v) => v,
         Err(e) => {
-            eprintln!("dat file {} has invalid format: {}", file, e);
+            eprintln!("load dat file {} failed: {}", file, e);
             return;
         }
     };
-    for i in site_group_list.site_group.iter() {
-        if i.tag.to_uppercase() == "CN" {
-            for domain in i.domain.iter() {
-                match domain.field_type {
-                    geosite::Domain_Type::Plain => {
-                        matcher.reverse_insert(domain.get_value(), MatchType::SubStr(true))
-                    }
-                    geosite::Domain_Type::Domain => {
-                        matcher.reverse_insert(domain.get_value(), MatchType::Domain(true))
-                    }
-                    geosite::Domain_Type::Full => {
-                        matcher.reverse_insert(domain.get_value(), MatchType::Full(true))
-                    }
-                    _ => {}
-                }
-            }
-        }
-    }
-    matcher.build();
+    geosite::insert_group(
+        matcher,
+        geosite::find_group(&site_group_list, "cn").unwrap(),
+    );
+    matcher.build().unwrap();
     for i in site_group_list.site_group.iter() {
         for domain in i.domain.iter() {
             match domain.field_type {
-                // we leave the regex match type to regex library.
+                // regex values are patterns rather than domains to query.
                 geosite::Domain_Type::Regex => {}
                 _ => {
                     if i.tag.to_uppercase() == "CN" {
@@ -138,3 +1097,991 @@ fn test_mph_matcher_with_geosite() {
     );
     println!("Hello, Mph DomainMatcher!");
 }
+
+#[test]
+fn test_group_matcher() {
+    let matcher = GroupMatcher::new(vec![
+        (
+            "cn",
+            vec![
+                ("163.com", MatchType::Domain(true)),
+                ("m.126.com", MatchType::Full(true)),
+                ("baidu", MatchType::SubStr(true)),
+            ],
+        ),
+        (
+            "ads",
+            vec![
+                ("ad.163.com", MatchType::Domain(true)),
+                ("163.com", MatchType::Full(true)),
+                ("ads", MatchType::SubStr(true)),
+            ],
+        ),
+        ("cn", vec![("qq.com", MatchType::Domain(true))]),
+    ])
+    .unwrap();
+    assert_eq!(matcher.tag_count(), 2);
+    assert_eq!(matcher.tag_id("CN"), Some(0));
+    assert_eq!(matcher.reverse_query_tags("163.com"), vec!["cn", "ads"]);
+    assert_eq!(matcher.reverse_query_tags("www.163.com"), vec!["cn"]);
+    assert_eq!(
+        matcher.reverse_query_tags("x.ad.163.com"),
+        vec!["cn", "ads"]
+    );
+    assert_eq!(
+        matcher.reverse_query_tags("ads.baidu.com"),
+        vec!["cn", "ads"]
+    );
+    assert_eq!(matcher.reverse_query_tags("m.qq.com"), vec!["cn"]);
+    assert!(matcher.reverse_query("126.com").is_empty());
+}
+
+#[test]
+fn test_group_matcher_case_and_exceptions() {
+    let matcher = GroupMatcher::new(vec![
+        (
+            "google",
+            vec![
+                ("Google.com", MatchType::Domain(true)),
+                ("ads.google.com", MatchType::Domain(false)),
+            ],
+        ),
+        ("search", vec![("google.com", MatchType::Domain(true))]),
+        (
+            "ads",
+            vec![
+                ("ads", MatchType::SubStr(true)),
+                ("ads.google.com", MatchType::Full(false)),
+            ],
+        ),
+    ])
+    .unwrap();
+    assert_eq!(
+        matcher.reverse_query_tags("www.google.com"),
+        vec!["google", "search"]
+    );
+    assert_eq!(
+        matcher.reverse_query_tags("x.ads.google.com"),
+        vec!["search", "ads"]
+    );
+    assert_eq!(matcher.reverse_query_tags("ads.google.com"), vec!["search"]);
+    assert_eq!(matcher.reverse_query_tags("ads.example.com"), vec!["ads"]);
+}
+
+#[test]
+fn test_group_matcher_opposite_polarity() {
+    let matcher = GroupMatcher::new(vec![
+        (
+            "a",
+            vec![
+                ("google.com", MatchType::Domain(true)),
+                ("ads.google.com", MatchType::Domain(false)),
+                ("track", MatchType::SubStr(false)),
+            ],
+        ),
+        (
+            "b",
+            vec![
+                ("ads.google.com", MatchType::Domain(true)),
+                ("track", MatchType::SubStr(true)),
+            ],
+        ),
+    ])
+    .unwrap();
+    assert_eq!(matcher.reverse_query_tags("www.google.com"), vec!["a"]);
+    assert_eq!(matcher.reverse_query_tags("x.ads.google.com"), vec!["b"]);
+    assert_eq!(matcher.reverse_query_tags("ads.google.com"), vec!["b"]);
+    assert_eq!(matcher.reverse_query_tags("track.example.com"), vec!["b"]);
+    // the Domain rule of `a` is more specific than its SubStr exception
+    assert_eq!(
+        matcher.reverse_query_tags("track.google.com"),
+        vec!["a", "b"]
+    );
+}
+
+#[test]
+fn test_group_matcher_with_geosite() {
+    let file = "data/geosite.dat";
+    let site_group_list = match geosite::load_site_group_list(file) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("load dat file {} failed: {}", file, e);
+            return;
+        }
+    };
+    let matcher = GroupMatcher::from_site_group_list(&site_group_list).unwrap();
+    for i in site_group_list.site_group.iter() {
+        let tag_id = matcher.tag_id(&i.tag).unwrap();
+        for domain in i.domain.iter() {
+            match domain.field_type {
+                geosite::Domain_Type::Regex => {}
+                _ => assert!(matcher.reverse_query(domain.get_value()).contains(tag_id)),
+            }
+        }
+    }
+    println!(
+        "Mem size of group matcher: {} mb, group count: {}",
+        matcher.deep_size_of() as f32 / (1024.0 * 1024.0),
+        matcher.tag_count()
+    );
+}
+
+#[cfg(test)]
+fn test_regex_rule(matcher: &mut impl DomainMatcher) {
+    matcher.reverse_insert("163.com", MatchType::Domain(true), ());
+    matcher.reverse_insert("google", MatchType::SubStr(true), ());
+    matcher.reverse_insert(
+        r"^ad[0-9]+\.example\.(com|net)$",
+        MatchType::Regex(true),
+        (),
+    );
+    matcher.reverse_insert(r"(", MatchType::Regex(true), ());
+    assert_eq!(
+        matcher.build(),
+        Err(BuildError::InvalidRegex("(".to_string()))
+    );
+    assert!(matcher.reverse_query("ad12.example.net"));
+    assert!(!matcher.reverse_query("ad.example.net"));
+    assert!(!matcher.reverse_query("xad1.example.com.cn"));
+    assert!(matcher.reverse_query("m.163.com"));
+    assert_eq!(
+        matcher.reverse_query_rule("ad1.example.com"),
+        Some(MatchedRule {
+            pattern: r"^ad[0-9]+\.example\.(com|net)$",
+            match_type: MatchType::Regex(true),
+            index: 2,
+            value: &()
+        })
+    );
+}
+
+#[test]
+fn test_regex_rule_all_matchers() {
+    test_regex_rule(&mut ACAutomaton::new(1));
+    test_regex_rule(&mut HybridMatcher::new(1));
+    test_regex_rule(&mut MphMatcher::new(1));
+}
+
+#[test]
+fn test_load_group() {
+    let file = "data/geosite.dat";
+    if !std::path::Path::new(file).exists() {
+        return;
+    }
+    let matcher = geosite::load_group(file, "Cn").unwrap();
+    assert!(matcher.reverse_query("163.com"));
+    assert!(!matcher.reverse_query("164.com"));
+    assert!(matches!(
+        geosite::load_group(file, "no-such-tag"),
+        Err(geosite::LoadError::UnknownTag(_))
+    ));
+    assert!(matches!(
+        geosite::load_group("data/no-such-file.dat", "cn"),
+        Err(geosite::LoadError::Io(_))
+    ));
+    assert!(matches!(
+        geosite::load_group("Cargo.toml", "cn"),
+        Err(geosite::LoadError::Protobuf(_))
+    ));
+}
+
+#[test]
+fn test_attribute_filter() {
+    let mut domain = geosite::Domain::new();
+    domain.set_value("google.cn".to_string());
+    let mut attr = geosite::Domain_Attribute::new();
+    attr.set_key("cn".to_string());
+    attr.set_bool_value(true);
+    domain.mut_attribute().push(attr);
+    let mut attr = geosite::Domain_Attribute::new();
+    attr.set_key("weight".to_string());
+    attr.set_int_value(3);
+    domain.mut_attribute().push(attr);
+    let mut attr = geosite::Domain_Attribute::new();
+    attr.set_key("ads".to_string());
+    attr.set_bool_value(false);
+    domain.mut_attribute().push(attr);
+
+    let matches = |selector: &str| {
+        let (_, filters) = geosite::parse_selector(selector).unwrap();
+        filters.iter().all(|filter| filter.matches(&domain))
+    };
+    assert!(matches("google"));
+    assert!(matches("google@cn"));
+    assert!(matches("google@CN"));
+    assert!(!matches("google@!cn"));
+    // the key alone decides, not its value
+    assert!(matches("google@ads"));
+    assert!(!matches("google@!ads"));
+    assert!(!matches("google@ads=0"));
+    assert!(!matches("google@other"));
+    assert!(matches("google@!other"));
+    assert!(matches("google@weight"));
+    assert!(matches("google@weight=3"));
+    assert!(!matches("google@weight=2"));
+    assert!(matches("google@!weight=2"));
+    assert!(matches("google@cn@weight=3"));
+    assert!(!matches("google@cn@!weight"));
+
+    assert_eq!(
+        geosite::parse_selector("geolocation-!cn").unwrap().0,
+        "geolocation-!cn"
+    );
+    assert!(geosite::parse_selector("google@").is_err());
+    assert!(geosite::parse_selector("@cn").is_err());
+    assert!(geosite::parse_selector("google@weight=x").is_err());
+}
+
+#[test]
+fn test_domain_display() {
+    let mut domain = geosite::Domain::new();
+    domain.set_field_type(geosite::Domain_Type::Domain);
+    domain.set_value("google.cn".to_string());
+    assert_eq!(domain.to_string(), "domain:google.cn");
+    let mut attr = geosite::Domain_Attribute::new();
+    attr.set_key("cn".to_string());
+    attr.set_bool_value(true);
+    domain.mut_attribute().push(attr);
+    let mut attr = geosite::Domain_Attribute::new();
+    attr.set_key("weight".to_string());
+    attr.set_int_value(3);
+    domain.mut_attribute().push(attr);
+    assert_eq!(domain.to_string(), "domain:google.cn @cn @weight=3");
+    domain.set_field_type(geosite::Domain_Type::Plain);
+    domain.clear_attribute();
+    assert_eq!(domain.to_string(), "keyword:google.cn");
+    domain.set_field_type(geosite::Domain_Type::Full);
+    assert_eq!(domain.to_string(), "full:google.cn");
+    domain.set_field_type(geosite::Domain_Type::Regex);
+    domain.set_value("^g.*\\.cn$".to_string());
+    assert_eq!(domain.to_string(), "regexp:^g.*\\.cn$");
+}
+
+#[test]
+fn test_insert_filtered_group() {
+    let mut group = geosite::SiteGroup::new();
+    group.set_tag("google".to_string());
+    for (value, cn) in [("google.cn", true), ("google.com", false), ("g.cn", true)] {
+        let mut domain = geosite::Domain::new();
+        domain.set_field_type(geosite::Domain_Type::Domain);
+        domain.set_value(value.to_string());
+        if cn {
+            let mut attr = geosite::Domain_Attribute::new();
+            attr.set_key("cn".to_string());
+            attr.set_bool_value(true);
+            domain.mut_attribute().push(attr);
+        }
+        group.mut_domain().push(domain);
+    }
+    let (_, filters) = geosite::parse_selector("google@cn").unwrap();
+    let mut with_cn = MphMatcher::new(1);
+    geosite::insert_filtered_group(&mut with_cn, &group, &filters);
+    with_cn.build().unwrap();
+    assert!(with_cn.reverse_query("www.google.cn"));
+    assert!(with_cn.reverse_query("g.cn"));
+    assert!(!with_cn.reverse_query("google.com"));
+
+    let (_, filters) = geosite::parse_selector("google@!cn").unwrap();
+    let mut without_cn = MphMatcher::new(1);
+    geosite::insert_filtered_group(&mut without_cn, &group, &filters);
+    without_cn.build().unwrap();
+    assert!(!without_cn.reverse_query("www.google.cn"));
+    assert!(without_cn.reverse_query("google.com"));
+
+    let (_, filters) = geosite::parse_selector("google@ads").unwrap();
+    let mut nothing = MphMatcher::new(1);
+    geosite::insert_filtered_group(&mut nothing, &group, &filters);
+    nothing.build().unwrap();
+    assert!(!nothing.reverse_query("google.com"));
+}
+
+#[test]
+fn test_clash_domain_provider() {
+    let yaml = "\
+# example rule provider
+payload:
+  - '+.google.com'
+  - \"youtube.com\"
+  - .ads.example.com # subdomains only
+  - '*.cdn.net'
+  - 'a+b.com'
+";
+    let mut matcher = MphMatcher::new(1);
+    let report = clash::insert_rules(&mut matcher, yaml, clash::Behavior::Domain, "proxy");
+    matcher.build().unwrap();
+    assert_eq!(report.inserted, 4);
+    assert_eq!(
+        report.skipped,
+        vec![SkippedLine {
+            line: 7,
+            text: "a+b.com".to_string(),
+            reason: SkipReason::Malformed,
+        }]
+    );
+    assert_eq!(matcher.reverse_query_value("google.com"), Some(&"proxy"));
+    assert!(matcher.reverse_query("mail.google.com"));
+    assert!(matcher.reverse_query("youtube.com"));
+    assert!(!matcher.reverse_query("www.youtube.com"));
+    assert!(matcher.reverse_query("x.ads.example.com"));
+    assert!(matcher.reverse_query("x.y.ads.example.com"));
+    assert!(!matcher.reverse_query("ads.example.com"));
+    assert!(matcher.reverse_query("img.cdn.net"));
+    assert!(!matcher.reverse_query("cdn.net"));
+    assert!(!matcher.reverse_query("a.img.cdn.net"));
+}
+
+#[test]
+fn test_clash_classical_provider() {
+    let text = "\
+DOMAIN,example.com
+DOMAIN-SUFFIX,google.com
+domain-keyword,tracker
+
+IP-CIDR,10.0.0.0/8,no-resolve
+DOMAIN-REGEX,^ad[0-9]+\\.net$
+DOMAIN-WILDCARD,img?.*.org
+PROCESS-NAME,curl
+DOMAIN,
+";
+    let mut matcher = MphMatcher::new(1);
+    let report = clash::insert_rules(&mut matcher, text, clash::Behavior::Classical, ());
+    matcher.build().unwrap();
+    assert_eq!(report.inserted, 5);
+    let skipped: Vec<usize> = report.skipped.iter().map(|line| line.line).collect();
+    assert_eq!(skipped, vec![5, 8, 9]);
+    assert_eq!(report.skipped[0].text, "IP-CIDR,10.0.0.0/8,no-resolve");
+    assert_eq!(report.skipped_count(SkipReason::Unsupported), 2);
+    assert_eq!(report.skipped_count(SkipReason::Malformed), 1);
+    assert!(matcher.reverse_query("example.com"));
+    assert!(!matcher.reverse_query("www.example.com"));
+    assert!(matcher.reverse_query("www.google.com"));
+    assert!(matcher.reverse_query("eu.tracker.io"));
+    assert!(matcher.reverse_query("ad42.net"));
+    assert!(matcher.reverse_query("img1.cdn.example.org"));
+    assert!(!matcher.reverse_query("img.org"));
+
+    assert_eq!(
+        clash::parse_entry("+.example.com", clash::Behavior::Domain),
+        Some(("example.com".to_string(), MatchType::Domain(true)))
+    );
+    assert_eq!(
+        clash::parse_entry("DOMAIN-SUFFIX,example.com", clash::Behavior::Domain),
+        None
+    );
+    assert_eq!(clash::parse_entry("+.", clash::Behavior::Domain), None);
+    assert_eq!(clash::parse_entry("a..com", clash::Behavior::Domain), None);
+}
+
+#[cfg(test)]
+fn test_abp_filter_list(matcher: &mut impl DomainMatcher) {
+    let list = "\
+[Adblock Plus 2.0]
+! Title: example list
+||ads.example.com^
+@@||good.ads.example.com^
+|http://tracker.net^
+||doubleclick.net^$important
+@@||doubleclick.net^
+||cdn*.example.org^
+/^banner[0-9]+\\./
+example.com##.banner
+example.com#@#.ad
+||example.com/ads/banner.js
+||example.com^$third-party
+0.0.0.0 hosts.example
+||exa mple.com^
+||metrics.io
+";
+    let report = abp::insert_rules(matcher, list, ());
+    matcher.build().unwrap();
+    assert_eq!(report.inserted, 7);
+    assert_eq!(report.skipped_count(SkipReason::Cosmetic), 2);
+    assert_eq!(report.skipped_count(SkipReason::UrlPath), 1);
+    assert_eq!(report.skipped_count(SkipReason::Unsupported), 3);
+    assert_eq!(report.skipped_count(SkipReason::Overridden), 1);
+    assert_eq!(report.skipped_count(SkipReason::Malformed), 0);
+    let overridden = report
+        .skipped
+        .iter()
+        .find(|line| line.reason == SkipReason::Overridden)
+        .unwrap();
+    assert_eq!(
+        (overridden.line, overridden.text.as_str()),
+        (7, "@@||doubleclick.net^")
+    );
+
+    assert!(matcher.reverse_query("ads.example.com"));
+    assert!(matcher.reverse_query("img.ads.example.com"));
+    assert!(!matcher.reverse_query("good.ads.example.com"));
+    assert!(matcher.reverse_query("tracker.net"));
+    assert!(!matcher.reverse_query("www.tracker.net"));
+    assert!(matcher.reverse_query("ad.doubleclick.net"));
+    assert!(matcher.reverse_query("cdn3.example.org"));
+    assert!(!matcher.reverse_query("example.org"));
+    assert!(matcher.reverse_query("banner12.example.net"));
+    assert!(!matcher.reverse_query("example.com"));
+    // without `^`, `||` only anchors the start of the name
+    assert!(matcher.reverse_query("a.metrics.io"));
+    assert!(matcher.reverse_query("metrics.iot"));
+    assert!(!matcher.reverse_query("xmetrics.io"));
+}
+
+#[test]
+fn test_abp_filter_list_all_matchers() {
+    test_abp_filter_list(&mut ACAutomaton::new(1));
+    test_abp_filter_list(&mut HybridMatcher::new(1));
+    test_abp_filter_list(&mut MphMatcher::new(1));
+}
+
+#[test]
+fn test_abp_important_exception() {
+    let list = "\
+||example.com^$important
+@@||cdn.example.com^$important
+||img.cdn.example.com^
+@@||www.example.com^
+|Ads.example.org^$important
+@@|ads.example.org^
+@@||ads.EXAMPLE.org^
+";
+    let mut matcher = MphMatcher::new(1);
+    let report = abp::insert_rules(&mut matcher, list, ());
+    matcher.build().unwrap();
+    let overridden: Vec<usize> = report.skipped.iter().map(|line| line.line).collect();
+    assert_eq!(overridden, vec![3, 4, 6]);
+    // a Full rule does not override the Domain exception
+    assert!(!matcher.reverse_query("x.ads.example.org"));
+    assert!(matcher.reverse_query("www.example.com"));
+    assert!(!matcher.reverse_query("cdn.example.com"));
+    assert!(!matcher.reverse_query("img.cdn.example.com"));
+    assert_eq!(
+        abp::parse_line("@@|https://example.com^$important"),
+        Ok(Some(abp::FilterRule {
+            pattern: "example.com".to_string(),
+            match_type: MatchType::Full(false),
+            important: true,
+        }))
+    );
+    assert_eq!(
+        abp::parse_line("||example.com"),
+        Ok(Some(abp::FilterRule {
+            pattern: r"(^|\.)example\.com".to_string(),
+            match_type: MatchType::Regex(true),
+            important: false,
+        }))
+    );
+    assert_eq!(abp::parse_line("! comment"), Ok(None));
+    assert_eq!(abp::parse_line("||.com^"), Err(SkipReason::Malformed));
+}
+
+#[test]
+fn test_hosts_file() {
+    let text = "\
+# blocklist
+127.0.0.1 localhost localhost.localdomain
+::1 ip6-localhost
+0.0.0.0 0.0.0.0
+0.0.0.0 ads.example.com tracker.example.com # inline comment
+:: ADS.example.com
+10.0.0.1\tnas.lan.
+fe80::1%lo0 link.local
+ads.example.org
+0.0.0.0 bad..name
+";
+    let mut matcher = MphMatcher::new(1);
+    let report = hosts::insert_rules(&mut matcher, text);
+    matcher.build().unwrap();
+    assert_eq!(report.inserted, 4);
+    let skipped: Vec<(usize, SkipReason)> = report
+        .skipped
+        .iter()
+        .map(|line| (line.line, line.reason))
+        .collect();
+    assert_eq!(
+        skipped,
+        vec![
+            (4, SkipReason::Unsupported),
+            (8, SkipReason::Unsupported),
+            (10, SkipReason::Malformed)
+        ]
+    );
+    let sinkhole = Target::Address(vec!["0.0.0.0".parse().unwrap()]);
+    assert_eq!(
+        matcher.reverse_query_value("ads.example.com"),
+        Some(&Target::Address(vec![
+            "0.0.0.0".parse().unwrap(),
+            "::".parse().unwrap()
+        ]))
+    );
+    assert_eq!(
+        matcher.reverse_query_value("tracker.example.com"),
+        Some(&sinkhole)
+    );
+    assert_eq!(matcher.reverse_query_value("x.ads.example.com"), None);
+    assert_eq!(
+        matcher.reverse_query_value("nas.lan"),
+        Some(&Target::Address(vec!["10.0.0.1".parse().unwrap()]))
+    );
+    assert_eq!(
+        matcher.reverse_query_value("ads.example.org"),
+        Some(&sinkhole)
+    );
+    assert_eq!(matcher.reverse_query_value("localhost"), None);
+    assert_eq!(matcher.reverse_query_value("0.0.0.0"), None);
+}
+
+#[test]
+fn test_dnsmasq_config() {
+    let text = "\
+# dnsmasq.d/blocklist.conf
+address=/ads.example.com/tracker.example.com/0.0.0.0
+address=/ads.example.com/::
+address=/doubleclick.net/::
+address=/nx.example.com/
+address=/null.example.com/#
+server=/corp.example/10.0.0.53
+server=/corp.example/10.0.0.54#5353
+local=/lan/
+server=1.1.1.1
+cache-size=1000
+address=/#/0.0.0.0
+address=/bad.example.com/not-an-ip
+server=/tracker.example.com/1.1.1.1
+";
+    let mut matcher = MphMatcher::new(1);
+    let report = dnsmasq::insert_rules(&mut matcher, text);
+    matcher.build().unwrap();
+    assert_eq!(report.inserted, 7);
+    assert_eq!(report.skipped_count(SkipReason::Unsupported), 3);
+    assert_eq!(report.skipped_count(SkipReason::Malformed), 1);
+    let overridden: Vec<usize> = report
+        .skipped
+        .iter()
+        .filter(|line| line.reason == SkipReason::Overridden)
+        .map(|line| line.line)
+        .collect();
+    assert_eq!(overridden, vec![14]);
+    let sinkhole = Target::Address(vec!["0.0.0.0".parse().unwrap()]);
+    let null = Target::Address(vec!["0.0.0.0".parse().unwrap(), "::".parse().unwrap()]);
+    assert_eq!(
+        matcher.reverse_query_value("img.ads.example.com"),
+        Some(&null)
+    );
+    assert_eq!(
+        matcher.reverse_query_value("tracker.example.com"),
+        Some(&sinkhole)
+    );
+    assert_eq!(
+        matcher.reverse_query_value("ad.doubleclick.net"),
+        Some(&Target::Address(vec!["::".parse().unwrap()]))
+    );
+    assert_eq!(
+        matcher.reverse_query_value("nx.example.com"),
+        Some(&Target::Block)
+    );
+    assert_eq!(matcher.reverse_query_value("null.example.com"), Some(&null));
+    assert_eq!(
+        matcher.reverse_query_value("git.corp.example"),
+        Some(&Target::Upstream(vec![
+            "10.0.0.53".to_string(),
+            "10.0.0.54#5353".to_string()
+        ]))
+    );
+    assert_eq!(
+        matcher.reverse_query_value("printer.lan"),
+        Some(&Target::Block)
+    );
+    assert_eq!(matcher.reverse_query_value("example.com"), None);
+}
+
+#[test]
+fn test_unbound_config() {
+    let text = "\
+server:
+    verbosity: 1
+    local-zone: \"ads.example.com.\" always_nxdomain
+    local-zone: tracker.example.com. redirect
+    local-data: \"www.tracker.example.com. 3600 IN A 0.0.0.0\"
+    local-data: \"www.tracker.example.com. AAAA ::\"
+    local-data: \"v6.example.com. AAAA ::1\"
+    local-data: \"mail.example.com. MX 10 mx.example.com.\"
+    local-data: \"ads.example.com. A 0.0.0.0\"
+
+forward-zone:
+    name: \"corp.example.\"
+    forward-addr: 10.0.0.53
+    forward-addr: 10.0.0.54@5353 # backup
+forward-zone:
+    name: \".\"
+    forward-addr: 1.1.1.1
+";
+    let mut matcher = MphMatcher::new(1);
+    let report = unbound::insert_rules(&mut matcher, text);
+    matcher.build().unwrap();
+    assert_eq!(report.inserted, 5);
+    let skipped: Vec<(usize, SkipReason)> = report
+        .skipped
+        .iter()
+        .map(|line| (line.line, line.reason))
+        .collect();
+    assert_eq!(
+        skipped,
+        vec![
+            (2, SkipReason::Unsupported),
+            (8, SkipReason::Unsupported),
+            (9, SkipReason::Overridden),
+            (15, SkipReason::Unsupported)
+        ]
+    );
+    assert_eq!(
+        matcher.reverse_query_value("x.ads.example.com"),
+        Some(&Target::LocalZone("always_nxdomain".to_string()))
+    );
+    // the Full rule is more specific than the zone
+    assert_eq!(
+        matcher.reverse_query_value("www.tracker.example.com"),
+        Some(&Target::Address(vec![
+            "0.0.0.0".parse().unwrap(),
+            "::".parse().unwrap()
+        ]))
+    );
+    assert_eq!(
+        matcher.reverse_query_value("img.tracker.example.com"),
+        Some(&Target::LocalZone("redirect".to_string()))
+    );
+    assert_eq!(
+        matcher.reverse_query_value("v6.example.com"),
+        Some(&Target::Address(vec!["::1".parse().unwrap()]))
+    );
+    assert_eq!(
+        matcher.reverse_query_value("git.corp.example"),
+        Some(&Target::Upstream(vec![
+            "10.0.0.53".to_string(),
+            "10.0.0.54@5353".to_string()
+        ]))
+    );
+}
+
+#[test]
+fn test_v2fly_sources() {
+    let mut sources = Sources::new();
+    sources.insert(
+        "google",
+        "\
+# Google
+google.com @ads
+full:www.google.cn @cn
+keyword:googleapis
+regexp:^gstatic[0-9]*\\.com$
+"
+        .to_string(),
+    );
+    sources.insert(
+        "Search",
+        "\
+include:google @-ads
+include:bing
+duckduckgo.com # no tracking
+"
+        .to_string(),
+    );
+    sources.insert("bing", "bing.com @cn\n".to_string());
+
+    let group = sources.compile_list("search").unwrap();
+    assert_eq!(group.get_tag(), "SEARCH");
+    let lines: Vec<String> = group.domain.iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        lines,
+        vec![
+            "full:www.google.cn @cn",
+            "keyword:googleapis",
+            "regexp:^gstatic[0-9]*\\.com$",
+            "domain:bing.com @cn",
+            "domain:duckduckgo.com",
+        ]
+    );
+    let list = sources.compile().unwrap();
+    let tags: Vec<&str> = list.site_group.iter().map(|g| g.get_tag()).collect();
+    assert_eq!(tags, vec!["BING", "GOOGLE", "SEARCH"]);
+
+    // what `write_list` emits parses back to the same domains
+    let mut text = Vec::new();
+    v2fly::write_list(&mut text, &group).unwrap();
+    let mut written = Sources::new();
+    written.insert("search", String::from_utf8(text).unwrap());
+    assert_eq!(written.compile_list("search").unwrap().domain, group.domain);
+
+    let mut matcher = MphMatcher::new(1);
+    geosite::insert_group(&mut matcher, &group);
+    matcher.build().unwrap();
+    assert!(matcher.reverse_query("www.bing.com"));
+    assert!(matcher.reverse_query("gstatic1.com"));
+    assert!(!matcher.reverse_query("google.com"));
+}
+
+#[test]
+fn test_v2fly_source_errors() {
+    let mut sources = Sources::new();
+    sources.insert("a", "a.com\ninclude:b\n".to_string());
+    sources.insert("b", "include:c\n".to_string());
+    sources.insert("c", "include:a\n".to_string());
+    sources.insert("d", "d.com\n\nbad:d.com\n".to_string());
+    sources.insert("e", "include:missing\n".to_string());
+    sources.insert("f", "regexp:(\n".to_string());
+    match sources.compile_list("a") {
+        Err(SourceError::IncludeCycle(cycle)) => assert_eq!(cycle, vec!["a", "b", "c", "a"]),
+        _ => panic!("expected an include cycle"),
+    }
+    assert!(matches!(
+        sources.compile_list("d"),
+        Err(SourceError::Syntax { line: 3, .. })
+    ));
+    assert!(matches!(
+        sources.compile_list("e"),
+        Err(SourceError::UnknownInclude { line: 1, .. })
+    ));
+    assert!(matches!(
+        sources.compile_list("f"),
+        Err(SourceError::Syntax { line: 1, .. })
+    ));
+    assert!(matches!(
+        sources.compile_list("g"),
+        Err(SourceError::Load(geosite::LoadError::UnknownTag(_)))
+    ));
+    assert!(sources.compile().is_err());
+    assert_eq!(v2fly::parse_line("  # comment"), Ok(None));
+    assert_eq!(
+        v2fly::parse_line("example.com @"),
+        Err(SkipReason::Malformed)
+    );
+}
+
+#[test]
+fn test_v2fly_load_group() {
+    let dir = std::env::temp_dir().join(format!("domain-matcher-v2fly-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("cn"), "include:baidu\nfull:qq.com @ads\n").unwrap();
+    std::fs::write(dir.join("baidu"), "baidu.com\nregexp:^bd[0-9]+\\.cn$\n").unwrap();
+    std::fs::write(dir.join(".hidden"), "not:a:list\n").unwrap();
+    let matcher = v2fly::load_group(&dir, "cn@!ads").unwrap();
+    let all = v2fly::load_group(&dir, "CN").unwrap();
+    let missing = v2fly::load_group(&dir, "nope");
+    std::fs::remove_dir_all(&dir).unwrap();
+    assert!(matcher.reverse_query("www.baidu.com"));
+    assert!(matcher.reverse_query("bd12.cn"));
+    assert!(!matcher.reverse_query("qq.com"));
+    assert!(all.reverse_query("qq.com"));
+    assert!(missing.is_err());
+}
+
+#[test]
+fn test_site_group_list_builder() {
+    let mut sources = Sources::new();
+    sources.insert(
+        "ads",
+        "b.com @ads @ads\nfull:a.com\nb.com @ads\n".to_string(),
+    );
+    sources.insert("cn", "qq.com\n".to_string());
+    let mut extra = geosite::SiteGroup::new();
+    extra.set_tag("Ads".to_string());
+    let mut domain = geosite::Domain::new();
+    domain.set_field_type(geosite::Domain_Type::Full);
+    domain.set_value("a.com".to_string());
+    extra.mut_domain().push(domain);
+
+    let mut builder = geosite::SiteGroupListBuilder::new();
+    builder
+        .add_site_group_list(sources.compile().unwrap())
+        .add_group(extra)
+        .add_domains("empty", Vec::new());
+    let list = builder.build();
+    let tags: Vec<&str> = list.site_group.iter().map(|g| g.get_tag()).collect();
+    assert_eq!(tags, vec!["ADS", "CN", "EMPTY"]);
+    let lines: Vec<String> = list.site_group[0]
+        .domain
+        .iter()
+        .map(|d| d.to_string())
+        .collect();
+    assert_eq!(lines, vec!["domain:b.com @ads", "full:a.com"]);
+
+    let mut dat = Vec::new();
+    geosite::write_site_group_list(&mut dat, &list).unwrap();
+    let read: geosite::SiteGroupList = protobuf::Message::parse_from_bytes(&dat).unwrap();
+    assert_eq!(read, list);
+}
+
+#[test]
+fn test_site_group_list_builder_with_geosite() {
+    let list = geosite::load_site_group_list("data/geosite.dat").unwrap();
+    let mut builder = geosite::SiteGroupListBuilder::new();
+    builder.add_site_group_list(list.clone());
+    let merged = builder.build();
+    assert_eq!(merged.site_group.len(), list.site_group.len());
+    let path = std::env::temp_dir().join(format!("domain-matcher-{}.dat", std::process::id()));
+    geosite::save_site_group_list(&path, &merged).unwrap();
+    let read = geosite::load_site_group_list(&path);
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(read.unwrap(), merged);
+    let mut matcher = MphMatcher::new(1);
+    geosite::insert_group(&mut matcher, geosite::find_group(&merged, "cn").unwrap());
+    matcher.build().unwrap();
+    assert!(matcher.reverse_query("www.baidu.com"));
+}
+
+#[test]
+fn test_mph_matcher_write_read() {
+    let mut mph_matcher = MphMatcher::new(1);
+    mph_matcher.reverse_insert("163.com", MatchType::Domain(true), ());
+    mph_matcher.reverse_insert("m.126.com", MatchType::Full(true), ());
+    mph_matcher.reverse_insert("google", MatchType::SubStr(true), ());
+    mph_matcher.reverse_insert(r"^ad[0-9]+\.example\.com$", MatchType::Regex(true), ());
+    mph_matcher.build().unwrap();
+    let mut bytes = Vec::new();
+    mph_matcher.write_to(&mut bytes).unwrap();
+
+    let loaded = MphMatcher::read_from(&mut bytes.as_slice()).unwrap();
+    for query in [
+        "163.com",
+        "m.163.com",
+        "m.126.com",
+        "126.com",
+        "www.google.com.hk",
+        "ad42.example.com",
+        "ad.example.com",
+    ] {
+        assert_eq!(
+            loaded.reverse_query_rule(query),
+            mph_matcher.reverse_query_rule(query)
+        );
+    }
+    let mut again = Vec::new();
+    loaded.write_to(&mut again).unwrap();
+    assert_eq!(again, bytes);
+
+    let mut corrupted = bytes.clone();
+    *corrupted.last_mut().unwrap() ^= 1;
+    assert!(matches!(
+        MphMatcher::read_from(&mut corrupted.as_slice()),
+        Err(FormatError::ChecksumMismatch)
+    ));
+    assert!(matches!(
+        MphMatcher::read_from(&mut &bytes[..bytes.len() - 4]),
+        Err(FormatError::Truncated)
+    ));
+    let mut other_version = bytes.clone();
+    other_version[4] = 0xff;
+    assert!(matches!(
+        MphMatcher::read_from(&mut other_version.as_slice()),
+        Err(FormatError::UnsupportedVersion(_))
+    ));
+    assert!(matches!(
+        MphMatcher::read_from(&mut &b"not an index at all"[..]),
+        Err(FormatError::BadMagic)
+    ));
+}
+
+#[test]
+fn test_mph_matcher_write_read_with_geosite() {
+    let file = "data/geosite.dat";
+    let site_group_list = match geosite::load_site_group_list(file) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("load dat file {} failed: {}", file, e);
+            return;
+        }
+    };
+    let mut mph_matcher = MphMatcher::new(1);
+    geosite::insert_group(
+        &mut mph_matcher,
+        geosite::find_group(&site_group_list, "cn").unwrap(),
+    );
+    mph_matcher.build().unwrap();
+    let mut bytes = Vec::new();
+    mph_matcher.write_to(&mut bytes).unwrap();
+    let loaded = MphMatcher::read_from(&mut bytes.as_slice()).unwrap();
+    for i in site_group_list.site_group.iter() {
+        for domain in i.domain.iter() {
+            assert_eq!(
+                loaded.reverse_query(domain.get_value()),
+                mph_matcher.reverse_query(domain.get_value())
+            );
+        }
+    }
+    println!(
+        "Index size of Mph matcher: {} mb",
+        bytes.len() as f32 / (1024.0 * 1024.0)
+    );
+}
+
+#[test]
+fn test_mph_matcher_ref() {
+    let mut mph_matcher = MphMatcher::new(1);
+    mph_matcher.reverse_insert("163.com", MatchType::Domain(true), ());
+    mph_matcher.reverse_insert("m.126.com", MatchType::Full(true), ());
+    mph_matcher.reverse_insert("google", MatchType::SubStr(true), ());
+    mph_matcher.reverse_insert("cdn.163.com", MatchType::Domain(false), ());
+    mph_matcher.build().unwrap();
+    let mut bytes = Vec::new();
+    mph_matcher.write_to(&mut bytes).unwrap();
+
+    let view = MphMatcherRef::new(&bytes).unwrap();
+    for query in [
+        "cdn.163.com",
+        "a.cdn.163.com",
+        "163.com",
+        "m.163.com",
+        "m.126.com",
+        "126.com",
+        "www.google.com.hk",
+        "example.com",
+    ] {
+        assert_eq!(
+            view.reverse_query_rule(query),
+            mph_matcher.reverse_query_rule(query)
+        );
+        assert_eq!(view.reverse_query(query), mph_matcher.reverse_query(query));
+    }
+
+    let mut with_regex = MphMatcher::new(1);
+    with_regex.reverse_insert(r"^ad[0-9]+\.example\.com$", MatchType::Regex(true), ());
+    with_regex.build().unwrap();
+    let mut bytes = Vec::new();
+    with_regex.write_to(&mut bytes).unwrap();
+    assert!(matches!(
+        MphMatcherRef::new(&bytes),
+        Err(FormatError::Unsupported(_))
+    ));
+    assert!(matches!(
+        MphMatcherRef::new(&bytes[..bytes.len() - 4]),
+        Err(FormatError::Truncated)
+    ));
+}
+
+#[test]
+fn test_mph_matcher_ref_with_geosite() {
+    let file = "data/geosite.dat";
+    let site_group_list = match geosite::load_site_group_list(file) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("load dat file {} failed: {}", file, e);
+            return;
+        }
+    };
+    let mut mph_matcher = MphMatcher::new(1);
+    geosite::insert_group(
+        &mut mph_matcher,
+        geosite::find_group(&site_group_list, "cn").unwrap(),
+    );
+    mph_matcher.build().unwrap();
+    let mut bytes = Vec::new();
+    mph_matcher.write_to(&mut bytes).unwrap();
+    let view = MphMatcherRef::new(&bytes).unwrap();
+    for i in site_group_list.site_group.iter() {
+        for domain in i.domain.iter() {
+            assert_eq!(
+                view.reverse_query_rule(domain.get_value()),
+                mph_matcher.reverse_query_rule(domain.get_value())
+            );
+        }
+    }
+}